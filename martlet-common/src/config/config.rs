@@ -6,6 +6,9 @@ use lazy_static::lazy_static;
 use serde::Deserialize;
 use serde::Serialize;
 
+/// Proxy nodes one cluster has at most, the generated keys hold their `worker_id` in 10 bits.
+pub const MAX_WORKERS: u32 = 1024;
+
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct MeshConfig {
     app: AppConfig,
@@ -15,7 +18,17 @@ pub struct MeshConfig {
 
 impl MeshConfig {
     pub fn from_str(config_str: &str) -> Self {
-        toml::from_str(config_str).unwrap()
+        Self::try_from_str(config_str).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Parses the node config and checks its `worker_id`, if given. The cluster config requires
+    /// one once a table generates SNOWFLAKE keys.
+    pub fn try_from_str(config_str: &str) -> Result<Self, String> {
+        let config: MeshConfig = toml::from_str(config_str).map_err(|e| format!("invalid node config: {}", e))?;
+        match config.system.worker_id {
+            Some(worker_id) if worker_id >= MAX_WORKERS => Err(format!("worker_id {} of the node config is not below {}", worker_id, MAX_WORKERS)),
+            _ => Ok(config),
+        }
     }
 
     pub fn from_file(config_file: &str) -> Self {
@@ -34,6 +47,16 @@ impl MeshConfig {
     pub fn get_port() -> u32 {
         MeshConfig::current().app.port
     }
+
+    /// Timeout in milliseconds of every statement the client sets none for, 0 leaves them unlimited.
+    pub fn get_statement_timeout() -> u32 {
+        MeshConfig::current().system.statement_timeout
+    }
+
+    /// Id of this proxy node, below `MAX_WORKERS`; `None` until a node config is made current.
+    pub fn get_worker_id() -> Option<u32> {
+        MeshConfig::current().system.worker_id
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
@@ -54,6 +77,12 @@ pub struct ControlConfig {
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct SystemConfig {
     timeout: u32,
+    /// Opt-in timeout of the statements sent to the segments, unlimited by default.
+    #[serde(default)]
+    statement_timeout: u32,
+    /// Id of this proxy node among the cluster's, unique to each, in the generated SNOWFLAKE keys.
+    #[serde(default)]
+    worker_id: Option<u32>,
 }
 
 impl MeshConfig {
@@ -68,4 +97,31 @@ impl MeshConfig {
 
 lazy_static! {
     static ref MESH_CONFIG_CACHE: RwLock<Arc<MeshConfig>> = RwLock::new(Default::default());
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::config::MeshConfig;
+
+    const CONFIG: &str = r#"
+[app]
+name = "Database Mesh"
+host = "localhost"
+port = 13306
+version = '0.1.0'
+[control]
+pilot = "localhost:6306"
+mixer = "localhost:7306"
+citadel = "localhost:8306"
+[system]
+timeout = 5000
+"#;
+
+    #[test]
+    fn test_worker_id() {
+        let config = MeshConfig::try_from_str(format!("{}worker_id = 1023\n", CONFIG).as_str()).unwrap();
+        assert_eq!(Some(1023), config.system.worker_id);
+        assert!(MeshConfig::try_from_str(format!("{}worker_id = 1024\n", CONFIG).as_str()).is_err());
+        assert_eq!(None, MeshConfig::try_from_str(CONFIG).unwrap().system.worker_id);
+    }
+}
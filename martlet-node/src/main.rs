@@ -12,6 +12,8 @@ use clap::{App, Arg, SubCommand};
 use toml::Value;
 
 use martlet_common::config::config::MeshConfig;
+use martlet_proxy::discovery::Cluster;
+use martlet_proxy::discovery::health::HealthChecker;
 
 mod service;
 mod config;
@@ -27,6 +29,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
             .multiple(true)
             .help("verbosity level"))
         .args_from_usage("-c, --config=[FILE] 'Target file you want to change'")
+        .args_from_usage("-r, --rules=[FILE] 'Cluster segments and distribution rules'")
         .subcommand(SubCommand::with_name("test")
             .about("does testing things")
             .arg_from_usage("-l, --list 'lists test values'"))
@@ -37,6 +40,9 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let config_path = matches.value_of("config").unwrap_or("./martlet-node/etc/node.toml");
     println!("config_path : {}", config_path);
 
+    let rules_path = matches.value_of("rules").unwrap_or("./martlet-node/etc/base.yaml");
+    println!("rules_path : {}", rules_path);
+
     if let Some(matches) = matches.subcommand_matches("test") {
         if matches.is_present("list") {
             println!("Printing testing lists...");
@@ -73,6 +79,10 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     println!("{:#?}", MeshConfig::current());
 
+    let cluster = Cluster::from_file(rules_path);
+    cluster.make_current();
+    HealthChecker::start();
+
     let service = service::new_service();
 
     service.serve().await
@@ -0,0 +1,225 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::discovery::CircuitBreakerConfig;
+use crate::error::ProxyError;
+
+/// State of a segment's circuit breaker.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum BreakerState {
+    /// Requests flow normally.
+    CLOSED,
+    /// Requests fail fast until `open_ms` passed.
+    OPEN,
+    /// A limited number of probe requests decide whether to close again.
+    HALF_OPEN,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CircuitBreaker {
+    url: String,
+    state: BreakerState,
+    #[serde(skip)]
+    failures: VecDeque<Instant>,
+    #[serde(skip)]
+    opened_at: Option<Instant>,
+    probes_in_flight: u32,
+    times_opened: u64,
+}
+
+impl CircuitBreaker {
+    fn new(url: String) -> Self {
+        CircuitBreaker {
+            url,
+            state: BreakerState::CLOSED,
+            failures: VecDeque::new(),
+            opened_at: None,
+            probes_in_flight: 0,
+            times_opened: 0,
+        }
+    }
+
+    pub fn get_url(&self) -> &String {
+        &self.url
+    }
+
+    pub fn get_state(&self) -> BreakerState {
+        self.state
+    }
+
+    pub fn get_recent_failures(&self) -> usize {
+        self.failures.len()
+    }
+
+    pub fn get_times_opened(&self) -> u64 {
+        self.times_opened
+    }
+
+    fn open(&mut self) {
+        if self.state != BreakerState::OPEN {
+            println!("breaker: segment {} {:?} -> OPEN after {} failures", self.url, self.state, self.failures.len());
+            self.times_opened = self.times_opened + 1;
+        }
+        self.state = BreakerState::OPEN;
+        self.opened_at = Some(Instant::now());
+        self.probes_in_flight = 0;
+    }
+
+    fn close(&mut self) {
+        if self.state != BreakerState::CLOSED {
+            println!("breaker: segment {} {:?} -> CLOSED", self.url, self.state);
+        }
+        self.state = BreakerState::CLOSED;
+        self.failures.clear();
+        self.opened_at = None;
+        self.probes_in_flight = 0;
+    }
+
+    fn open_elapsed(&self, config: &CircuitBreakerConfig) -> bool {
+        match self.opened_at {
+            Some(opened_at) => opened_at.elapsed() >= Duration::from_millis(config.get_open_ms()),
+            None => true,
+        }
+    }
+}
+
+lazy_static! {
+    /// Breakers keyed by segment url, segments without one are CLOSED.
+    static ref CIRCUIT_BREAKERS: RwLock<HashMap<String, CircuitBreaker>> = RwLock::new(HashMap::new());
+}
+
+/// Lets a request through to the segment, or fails fast while its breaker is open.
+/// In HALF_OPEN only `half_open_probes` requests at a time get through.
+pub fn acquire(url: &str, config: &CircuitBreakerConfig) -> Result<(), ProxyError> {
+    if !config.is_enabled() {
+        return Ok(());
+    }
+    let mut breakers = CIRCUIT_BREAKERS.write().unwrap();
+    let breaker = match breakers.get_mut(url) {
+        Some(breaker) => breaker,
+        None => return Ok(()),
+    };
+    if breaker.state == BreakerState::OPEN && breaker.open_elapsed(config) {
+        println!("breaker: segment {} OPEN -> HALF_OPEN", breaker.url);
+        breaker.state = BreakerState::HALF_OPEN;
+        breaker.probes_in_flight = 0;
+    }
+    match breaker.state {
+        BreakerState::CLOSED => Ok(()),
+        BreakerState::HALF_OPEN if breaker.probes_in_flight < config.get_half_open_probes() => {
+            breaker.probes_in_flight = breaker.probes_in_flight + 1;
+            Ok(())
+        }
+        _ => Err(ProxyError::CircuitOpen(url.to_string())),
+    }
+}
+
+/// Whether requests to the segment currently fail fast, without taking a probe slot.
+pub fn is_open(url: &str, config: &CircuitBreakerConfig) -> bool {
+    if !config.is_enabled() {
+        return false;
+    }
+    match CIRCUIT_BREAKERS.read().unwrap().get(url) {
+        Some(breaker) => breaker.state == BreakerState::OPEN && !breaker.open_elapsed(config),
+        None => false,
+    }
+}
+
+pub fn record_success(url: &str) {
+    if let Some(breaker) = CIRCUIT_BREAKERS.write().unwrap().get_mut(url) {
+        if breaker.state == BreakerState::HALF_OPEN {
+            breaker.close();
+        }
+    }
+}
+
+/// Counts an error or timeout; `failure_threshold` of them within `window_ms` open the breaker,
+/// and any failure of a HALF_OPEN probe opens it again.
+pub fn record_failure(url: &str, config: &CircuitBreakerConfig) {
+    if !config.is_enabled() {
+        return;
+    }
+    let mut breakers = CIRCUIT_BREAKERS.write().unwrap();
+    let breaker = breakers.entry(url.to_string()).or_insert_with(|| CircuitBreaker::new(url.to_string()));
+    let now = Instant::now();
+    breaker.failures.push_back(now);
+    let window = Duration::from_millis(config.get_window_ms());
+    while let Some(oldest) = breaker.failures.front() {
+        if now.duration_since(*oldest) > window {
+            breaker.failures.pop_front();
+        } else {
+            break;
+        }
+    }
+    match breaker.state {
+        BreakerState::HALF_OPEN => breaker.open(),
+        BreakerState::CLOSED if breaker.failures.len() as u32 >= config.get_failure_threshold() => breaker.open(),
+        _ => {}
+    }
+}
+
+/// Feeds the periodic health ping into a breaker which is not CLOSED: a success after
+/// `open_ms` closes it, a failure keeps it open.
+pub fn probe(url: &str, ok: bool, config: &CircuitBreakerConfig) {
+    let mut breakers = CIRCUIT_BREAKERS.write().unwrap();
+    if let Some(breaker) = breakers.get_mut(url) {
+        match (breaker.state, ok) {
+            (BreakerState::CLOSED, _) => {}
+            (_, true) => {
+                if breaker.open_elapsed(config) {
+                    breaker.close();
+                }
+            }
+            (_, false) => breaker.open(),
+        }
+    }
+}
+
+/// Snapshot of every breaker, for the admin tooling.
+pub fn snapshot() -> Vec<CircuitBreaker> {
+    let mut breakers: Vec<CircuitBreaker> = CIRCUIT_BREAKERS.read().unwrap().values().cloned().collect();
+    breakers.sort_by(|a, b| a.url.cmp(&b.url));
+    breakers
+}
+
+/// Whether the error says the segment itself is unhealthy, as opposed to the statement being wrong.
+pub fn is_segment_failure(e: &mysql::Error) -> bool {
+    match *e {
+        mysql::error::Error::MySqlError(ref err) => match err.code {
+            // ER_CON_COUNT_ERROR, ER_SERVER_SHUTDOWN, ER_OPTION_PREVENTS_STATEMENT (read only)
+            1040 | 1053 | 1290 => true,
+            // Lock waits and statement timeouts come from the workload, not the segment.
+            _ => false,
+        },
+        mysql::error::Error::UrlError(_) => false,
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::discovery::breaker::{acquire, is_open, record_failure, record_success, BreakerState, snapshot};
+    use crate::discovery::CircuitBreakerConfig;
+
+    #[test]
+    fn test_breaker() {
+        let url = "jdbc:mysql://127.0.0.1:3306/breaker_test";
+        let config: CircuitBreakerConfig = serde_yaml::from_str("{ failure_threshold: 2, open_ms: 60000 }").unwrap();
+
+        record_failure(url, &config);
+        assert!(acquire(url, &config).is_ok());
+        record_failure(url, &config);
+        assert!(is_open(url, &config));
+        assert!(acquire(url, &config).is_err());
+
+        let config: CircuitBreakerConfig = serde_yaml::from_str("{ failure_threshold: 2, open_ms: 0 }").unwrap();
+        assert!(acquire(url, &config).is_ok());
+        assert!(acquire(url, &config).is_err());
+        record_success(url);
+        let state = snapshot().into_iter().find(|b| b.get_url() == url).unwrap().get_state();
+        assert_eq!(state, BreakerState::CLOSED);
+    }
+}
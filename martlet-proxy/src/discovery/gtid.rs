@@ -0,0 +1,77 @@
+//! GTID sets as reported by `@@GLOBAL.gtid_executed`, e.g.
+//! `3e11fa47-71ca-11e1-9e33-c80aa9429562:1-5:11, 5ade9f8e-71ca-11e1-9e33-c80aa9429562:1-3`.
+
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct GtidSet {
+    intervals: HashMap<String, Vec<(u64, u64)>>,
+}
+
+impl GtidSet {
+    pub fn parse(gtid_set: &str) -> Self {
+        let mut intervals: HashMap<String, Vec<(u64, u64)>> = HashMap::new();
+        for part in gtid_set.split(',') {
+            let part: String = part.chars().filter(|c| !c.is_whitespace()).collect();
+            let mut fields = part.split(':');
+            let uuid = match fields.next() {
+                Some(uuid) if !uuid.is_empty() => uuid.to_lowercase(),
+                _ => continue,
+            };
+            let ranges = intervals.entry(uuid).or_insert_with(Vec::new);
+            for range in fields {
+                let mut bounds = range.splitn(2, '-');
+                let start = bounds.next().and_then(|b| b.parse::<u64>().ok());
+                let end = match bounds.next() {
+                    Some(b) => b.parse::<u64>().ok(),
+                    None => start,
+                };
+                if let (Some(start), Some(end)) = (start, end) {
+                    ranges.push((start, end));
+                }
+            }
+            ranges.sort();
+        }
+        GtidSet { intervals }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.intervals.values().all(|ranges| ranges.is_empty())
+    }
+
+    /// Whether every transaction of `other` is part of this set.
+    pub fn contains(&self, other: &GtidSet) -> bool {
+        other.intervals.iter().all(|(uuid, ranges)| {
+            let own = self.intervals.get(uuid);
+            ranges.iter().all(|&(start, end)| covered(own, start, end))
+        })
+    }
+}
+
+fn covered(own: Option<&Vec<(u64, u64)>>, start: u64, end: u64) -> bool {
+    let mut next = start;
+    if let Some(own) = own {
+        for &(own_start, own_end) in own {
+            if own_start <= next && own_end >= next {
+                next = own_end + 1;
+            }
+        }
+    }
+    next > end
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::discovery::gtid::GtidSet;
+
+    #[test]
+    fn test_contains() {
+        let executed = GtidSet::parse("3E11FA47-71CA-11E1-9E33-C80AA9429562:1-5:7-9,\n5ade9f8e-71ca-11e1-9e33-c80aa9429562:1-3");
+        assert!(executed.contains(&GtidSet::parse("3e11fa47-71ca-11e1-9e33-c80aa9429562:4")));
+        assert!(executed.contains(&GtidSet::parse("3e11fa47-71ca-11e1-9e33-c80aa9429562:1-5:8")));
+        assert!(executed.contains(&GtidSet::parse("")));
+        assert!(!executed.contains(&GtidSet::parse("3e11fa47-71ca-11e1-9e33-c80aa9429562:6")));
+        assert!(!executed.contains(&GtidSet::parse("3e11fa47-71ca-11e1-9e33-c80aa9429562:5-7")));
+        assert!(!executed.contains(&GtidSet::parse("4e11fa47-71ca-11e1-9e33-c80aa9429562:1")));
+    }
+}
@@ -0,0 +1,344 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::RwLock;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use mysql::{Conn, Opts, OptsBuilder, Row};
+use mysql::prelude::Queryable;
+use serde::{Deserialize, Serialize};
+
+use crate::discovery::{breaker, Cluster, HealthCheckConfig, Segment, SegmentGroup};
+use crate::discovery::gtid::GtidSet;
+
+/// Health of a single primary or mirror as seen by the checker.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum SegmentStatus {
+    UP,
+    DEGRADED,
+    DOWN,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SegmentHealth {
+    group: SegmentGroup,
+    url: String,
+    status: SegmentStatus,
+    failures: u32,
+    latency_ms: u64,
+    /// Replication lag of a mirror, `None` while unknown or replication is stopped.
+    lag_ms: Option<u64>,
+    /// `@@GLOBAL.gtid_executed` of a mirror as of the last check.
+    gtid_executed: String,
+    last_error: String,
+    #[serde(skip)]
+    down_since: Option<Instant>,
+}
+
+impl SegmentHealth {
+    fn new(group: SegmentGroup, url: String) -> Self {
+        SegmentHealth {
+            group,
+            url,
+            status: SegmentStatus::UP,
+            failures: 0,
+            latency_ms: 0,
+            lag_ms: None,
+            gtid_executed: "".to_string(),
+            last_error: "".to_string(),
+            down_since: None,
+        }
+    }
+
+    pub fn get_group(&self) -> SegmentGroup {
+        self.group
+    }
+
+    pub fn get_url(&self) -> &String {
+        &self.url
+    }
+
+    pub fn get_status(&self) -> SegmentStatus {
+        self.status
+    }
+
+    pub fn get_failures(&self) -> u32 {
+        self.failures
+    }
+
+    pub fn get_latency_ms(&self) -> u64 {
+        self.latency_ms
+    }
+
+    pub fn get_lag_ms(&self) -> Option<u64> {
+        self.lag_ms
+    }
+
+    pub fn get_gtid_executed(&self) -> &String {
+        &self.gtid_executed
+    }
+
+    pub fn get_last_error(&self) -> &String {
+        &self.last_error
+    }
+}
+
+lazy_static! {
+    /// Health keyed by segment url, segments which were never checked count as UP.
+    static ref SEGMENT_HEALTH: RwLock<HashMap<String, SegmentHealth>> = RwLock::new(HashMap::new());
+    /// Mirrors promoted to primary by the failover, keyed by group.
+    static ref PROMOTED_PRIMARIES: RwLock<HashMap<SegmentGroup, String>> = RwLock::new(HashMap::new());
+    static ref READ_ROUND_ROBIN: AtomicUsize = AtomicUsize::new(0);
+}
+
+pub fn status_of(segment: &Segment) -> SegmentStatus {
+    match SEGMENT_HEALTH.read().unwrap().get(segment.get_url()) {
+        Some(health) => health.status,
+        None => SegmentStatus::UP,
+    }
+}
+
+/// Whether the mirror is known to be at most `max_lag_ms` behind its primary.
+pub fn within_lag(segment: &Segment, max_lag_ms: Option<u64>) -> bool {
+    match max_lag_ms {
+        None => true,
+        Some(max_lag_ms) => match SEGMENT_HEALTH.read().unwrap().get(segment.get_url()) {
+            Some(SegmentHealth { lag_ms: Some(lag_ms), .. }) => *lag_ms <= max_lag_ms,
+            _ => false,
+        },
+    }
+}
+
+/// Whether the mirror had executed every transaction of `gtid` as of its last check.
+/// `gtid_executed` only grows, so a stale answer is never wrongly positive.
+pub fn has_executed(segment: &Segment, gtid: &GtidSet) -> bool {
+    match SEGMENT_HEALTH.read().unwrap().get(segment.get_url()) {
+        Some(health) => GtidSet::parse(health.gtid_executed.as_str()).contains(gtid),
+        None => false,
+    }
+}
+
+/// Snapshot of every checked segment, for the admin tooling.
+pub fn snapshot() -> Vec<SegmentHealth> {
+    let mut healths: Vec<SegmentHealth> = SEGMENT_HEALTH.read().unwrap().values().cloned().collect();
+    healths.sort_by(|a, b| a.url.cmp(&b.url));
+    healths
+}
+
+/// Mirrors currently acting as primary, for the admin tooling.
+pub fn promoted_primaries() -> HashMap<SegmentGroup, String> {
+    PROMOTED_PRIMARIES.read().unwrap().clone()
+}
+
+/// Hands the group back to its configured primary once the operator has repaired it.
+pub fn clear_promotion(group: SegmentGroup) {
+    if let Some(url) = PROMOTED_PRIMARIES.write().unwrap().remove(&group) {
+        println!("health: {:?} primary restored, mirror {} demoted", group, url);
+    }
+}
+
+/// The segment writes of the group go to: the configured primary, or the mirror promoted in its place.
+pub fn primary_of(cluster: &Cluster, group: SegmentGroup) -> Option<&Segment> {
+    let (primary, mirrors) = cluster.get_segments().get_group(group)?;
+    if let Some(url) = PROMOTED_PRIMARIES.read().unwrap().get(&group) {
+        if let Some(mirror) = mirrors.iter().find(|m| m.get_url() == url) {
+            return Some(mirror);
+        }
+    }
+    Some(primary)
+}
+
+/// The segment reads of the group go to: an UP mirror, else a DEGRADED one, else the primary.
+/// DOWN mirrors and mirrors lagging more than `max_lag_ms` are never chosen.
+pub fn read_segment_of(cluster: &Cluster, group: SegmentGroup, max_lag_ms: Option<u64>) -> Option<&Segment> {
+    pick_read_segment(cluster, group, |m| within_lag(m, max_lag_ms))
+}
+
+/// Like `read_segment_of`, but only mirrors which already executed `gtid` qualify.
+pub fn read_segment_containing<'a>(cluster: &'a Cluster, group: SegmentGroup, max_lag_ms: Option<u64>, gtid: &GtidSet) -> Option<&'a Segment> {
+    pick_read_segment(cluster, group, |m| within_lag(m, max_lag_ms) && has_executed(m, gtid))
+}
+
+fn pick_read_segment<F>(cluster: &Cluster, group: SegmentGroup, accept: F) -> Option<&Segment>
+    where
+        F: Fn(&Segment) -> bool,
+{
+    let primary = primary_of(cluster, group)?;
+    let (_, mirrors) = cluster.get_segments().get_group(group)?;
+    let breaker_config = cluster.get_circuit_breaker();
+    let candidates: Vec<&Segment> = mirrors.iter()
+        .filter(|m| m.get_url() != primary.get_url() && !breaker::is_open(m.get_url(), breaker_config))
+        .filter(|m| accept(m))
+        .collect();
+    for wanted in &[SegmentStatus::UP, SegmentStatus::DEGRADED] {
+        let healthy: Vec<&Segment> = candidates.iter()
+            .filter(|m| status_of(m) == *wanted)
+            .cloned()
+            .collect();
+        if !healthy.is_empty() {
+            let next = READ_ROUND_ROBIN.fetch_add(1, Ordering::SeqCst);
+            return Some(healthy[next % healthy.len()]);
+        }
+    }
+    Some(primary)
+}
+
+/// Pings every primary and mirror of the current cluster in the background.
+pub struct HealthChecker {}
+
+impl HealthChecker {
+    pub fn start() -> thread::JoinHandle<()> {
+        thread::spawn(|| {
+            loop {
+                let cluster = Cluster::current();
+                let config = cluster.get_health_check().clone();
+                if config.is_enabled() {
+                    for group in cluster.get_segments().get_groups() {
+                        check_group(&cluster, group, &config);
+                    }
+                }
+                thread::sleep(Duration::from_millis(config.get_interval_ms()));
+            }
+        })
+    }
+}
+
+fn check_group(cluster: &Cluster, group: SegmentGroup, config: &HealthCheckConfig) {
+    if let Some((primary, mirrors)) = cluster.get_segments().get_group(group) {
+        check_segment(cluster, group, primary, false, config);
+        for mirror in mirrors {
+            if mirror.get_url() != primary.get_url() {
+                check_segment(cluster, group, mirror, true, config);
+            }
+        }
+    }
+    if let Some(grace_ms) = config.get_failover_grace_ms() {
+        failover(cluster, group, Duration::from_millis(grace_ms));
+    }
+}
+
+fn check_segment(cluster: &Cluster, group: SegmentGroup, segment: &Segment, mirror: bool, config: &HealthCheckConfig) {
+    let result = ping(segment, mirror, config);
+    breaker::probe(segment.get_url(), result.is_ok(), cluster.get_circuit_breaker());
+
+    let mut healths = SEGMENT_HEALTH.write().unwrap();
+    let health = healths.entry(segment.get_url().clone())
+        .or_insert_with(|| SegmentHealth::new(group, segment.get_url().clone()));
+    let before = health.status;
+    match result {
+        Ok(probe) => {
+            health.failures = 0;
+            health.latency_ms = probe.latency.as_millis() as u64;
+            health.lag_ms = probe.lag_ms;
+            health.gtid_executed = probe.gtid_executed;
+            health.last_error = "".to_string();
+            health.status = if health.latency_ms > config.get_degraded_latency_ms() {
+                SegmentStatus::DEGRADED
+            } else {
+                SegmentStatus::UP
+            };
+        }
+        Err(e) => {
+            health.failures = health.failures + 1;
+            health.lag_ms = None;
+            health.last_error = e;
+            health.status = if health.failures >= config.get_fall_threshold() {
+                SegmentStatus::DOWN
+            } else {
+                SegmentStatus::DEGRADED
+            };
+        }
+    }
+    if health.status == SegmentStatus::DOWN {
+        if health.down_since.is_none() {
+            health.down_since = Some(Instant::now());
+        }
+    } else {
+        health.down_since = None;
+    }
+    if before != health.status {
+        println!("health: {:?} segment {} changed {:?} -> {:?} (latency = {}ms, error = {:?})",
+                 group, health.url, before, health.status, health.latency_ms, health.last_error);
+    }
+}
+
+struct Probe {
+    latency: Duration,
+    lag_ms: Option<u64>,
+    gtid_executed: String,
+}
+
+/// Round trip of `SELECT 1` and, for mirrors, the replication lag and executed GTIDs.
+fn ping(segment: &Segment, mirror: bool, config: &HealthCheckConfig) -> Result<Probe, String> {
+    let timeout = Duration::from_millis(config.get_timeout_ms());
+    let started = Instant::now();
+    let opts = Opts::from_url(segment.database_url().as_str()).map_err(|e| e.to_string())?;
+    let opts = OptsBuilder::from_opts(opts)
+        .tcp_connect_timeout(Some(timeout))
+        .read_timeout(Some(timeout))
+        .write_timeout(Some(timeout));
+    let mut conn = Conn::new(opts).map_err(|e| e.to_string())?;
+    conn.query_drop("SELECT 1").map_err(|e| e.to_string())?;
+    let latency = started.elapsed();
+    if !mirror {
+        return Ok(Probe { latency, lag_ms: None, gtid_executed: "".to_string() });
+    }
+    let lag_ms = replication_lag_ms(&mut conn, config)?;
+    let gtid_executed: Option<String> = conn.query_first("SELECT @@GLOBAL.gtid_executed").map_err(|e| e.to_string())?;
+    Ok(Probe { latency, lag_ms, gtid_executed: gtid_executed.unwrap_or_default() })
+}
+
+fn replication_lag_ms(conn: &mut Conn, config: &HealthCheckConfig) -> Result<Option<u64>, String> {
+    match config.get_heartbeat_table() {
+        Some(table) => {
+            let sql = format!("SELECT TIMESTAMPDIFF(MICROSECOND, MAX({}), NOW(6)) FROM {}",
+                              config.get_heartbeat_column(), table);
+            let lag_us: Option<Option<i64>> = conn.query_first(sql).map_err(|e| e.to_string())?;
+            Ok(lag_us.flatten().map(|us| us.max(0) as u64 / 1000))
+        }
+        None => {
+            let status: Option<Row> = conn.query_first("SHOW SLAVE STATUS").map_err(|e| e.to_string())?;
+            Ok(status
+                .and_then(|row| row.get::<Option<u64>, _>("Seconds_Behind_Master"))
+                .flatten()
+                .map(|seconds| seconds * 1000))
+        }
+    }
+}
+
+/// Promotes the first UP mirror once the acting primary has been DOWN for the grace period.
+fn failover(cluster: &Cluster, group: SegmentGroup, grace: Duration) {
+    let primary = match primary_of(cluster, group) {
+        Some(primary) => primary,
+        None => return,
+    };
+    let down_for = match SEGMENT_HEALTH.read().unwrap().get(primary.get_url()) {
+        Some(SegmentHealth { status: SegmentStatus::DOWN, down_since: Some(since), .. }) => since.elapsed(),
+        _ => return,
+    };
+    if down_for < grace {
+        return;
+    }
+    let (_, mirrors) = cluster.get_segments().get_group(group).unwrap();
+    let candidate = mirrors.iter()
+        .find(|m| m.get_url() != primary.get_url() && status_of(m) == SegmentStatus::UP);
+    match candidate {
+        Some(mirror) => match promote(mirror) {
+            Ok(()) => {
+                PROMOTED_PRIMARIES.write().unwrap().insert(group, mirror.get_url().clone());
+                println!("health: {:?} primary {} DOWN for {:?}, promoted mirror {}",
+                         group, primary.get_url(), down_for, mirror.get_url());
+            }
+            Err(e) => println!("health: {:?} failed to promote mirror {}; error = {}", group, mirror.get_url(), e),
+        },
+        None => println!("health: {:?} primary {} DOWN for {:?}, no healthy mirror to promote",
+                         group, primary.get_url(), down_for),
+    }
+}
+
+fn promote(mirror: &Segment) -> Result<(), String> {
+    let mut conn = Conn::new(mirror.database_url().as_str()).map_err(|e| e.to_string())?;
+    conn.query_drop("STOP SLAVE").map_err(|e| e.to_string())?;
+    conn.query_drop("SET GLOBAL read_only = OFF").map_err(|e| e.to_string())?;
+    Ok(())
+}
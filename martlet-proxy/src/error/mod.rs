@@ -0,0 +1,67 @@
+use std::fmt;
+
+/// Error code answered while a segment's circuit breaker is open.
+pub const ER_CIRCUIT_OPEN: u32 = 9001;
+/// MySQL's ER_QUERY_TIMEOUT, answered when the proxy killed a statement which ran too long.
+pub const ER_QUERY_TIMEOUT: u32 = 3024;
+/// MySQL's ER_NOT_SUPPORTED_YET.
+pub const ER_NOT_SUPPORTED_YET: u32 = 1235;
+/// MySQL's ER_WRONG_ARGUMENTS.
+pub const ER_WRONG_ARGUMENTS: u32 = 1210;
+
+/// Errors the proxy answers to the client with a `MySQLErrPacket`.
+#[derive(Debug)]
+pub enum ProxyError {
+    /// The backend or the driver failed.
+    Backend(mysql::Error),
+    /// The segment's circuit breaker is open, the statement was not sent.
+    CircuitOpen(String),
+    /// The statement ran longer than its timeout in milliseconds and was killed.
+    QueryTimeout(u64),
+    /// The statement is valid MySQL the proxy cannot execute over the cluster, names what.
+    Unsupported(String),
+    /// The client sent arguments which do not fit the command, names the command.
+    WrongArguments(String),
+}
+
+impl ProxyError {
+    /// Error code, SQL state and message of the ERR packet.
+    pub fn to_err_parts(&self) -> (u32, String, String) {
+        match self {
+            ProxyError::Backend(e) => match *e {
+                mysql::error::Error::IoError(ref err) => (10000 as u32, err.to_string(), err.to_string()),
+                mysql::error::Error::DriverError(ref err) => (20000, err.to_string(), err.to_string()),
+                mysql::error::Error::MySqlError(ref err) => (err.code as u32, String::from(err.state.as_str()), String::from(err.message.as_str())),
+                mysql::error::Error::UrlError(ref err) => (40000, err.to_string(), err.to_string()),
+                mysql::error::Error::TlsError(ref err) => (50000, err.to_string(), err.to_string()),
+                mysql::error::Error::TlsHandshakeError(ref err) => (60000, err.to_string(), err.to_string()),
+                _ => (70000, String::from("unknown exception"), String::from("unknown exception")),
+            },
+            ProxyError::CircuitOpen(url) => (ER_CIRCUIT_OPEN,
+                                             String::from("HY000"),
+                                             format!("Segment {} is unavailable: circuit breaker is open", url)),
+            ProxyError::QueryTimeout(_) => (ER_QUERY_TIMEOUT,
+                                            String::from("HY000"),
+                                            String::from("Query execution was interrupted, maximum statement execution time exceeded")),
+            ProxyError::Unsupported(what) => (ER_NOT_SUPPORTED_YET,
+                                              String::from("42000"),
+                                              format!("This version of MartletBase doesn't yet support '{}'", what)),
+            ProxyError::WrongArguments(command) => (ER_WRONG_ARGUMENTS,
+                                                    String::from("HY000"),
+                                                    format!("Incorrect arguments to {}", command)),
+        }
+    }
+}
+
+impl fmt::Display for ProxyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (code, state, message) = self.to_err_parts();
+        write!(f, "ERROR {} ({}): {}", code, state, message)
+    }
+}
+
+impl From<mysql::Error> for ProxyError {
+    fn from(e: mysql::Error) -> Self {
+        ProxyError::Backend(e)
+    }
+}
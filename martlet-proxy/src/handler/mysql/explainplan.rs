@@ -1,7 +1,21 @@
+use std::collections::HashMap;
+
 use bytes::Bytes;
-use sqlparser::ast::Statement;
+use sqlparser::ast::{Query, Select, SetExpr, SetOperator, Statement};
+
+use martlet_common::common::Error;
 
-use crate::handler::mysql::rdbc::{bin_query, text_query};
+use crate::discovery::{Cluster, SegmentGroup};
+use crate::error::ProxyError;
+use crate::handler::mysql::rdbc::{bin_query, error_payload, text_query};
+use crate::handler::mysql::xa::ends_transaction;
+use crate::handler::parser::sql::analyse::SQLAnalyse;
+use crate::handler::parser::sql::mysql::RouteHints;
+use crate::handler::parser::sql::rewrite::SQLReWrite;
+use crate::handler::parser::sql::route::{KeyMove, logical_table_name, physical_tables, route, RouteContext, RouteUnit, split_insert, statement_schema};
+use crate::handler::parser::sql::route::value::ShardValue;
+use crate::handler::parser::sql::{SelectStatementContext, SQLStatementContext};
+use crate::session::mysql::SessionContext;
 
 pub enum TBProtocol {
     Text,
@@ -12,6 +26,11 @@ pub struct ExplainPlanContext<'a> {
     sql: &'a str,
     statement: &'a Statement,
     protocol: TBProtocol,
+    max_lag_ms: Option<u64>,
+    timeout_ms: Option<u64>,
+    parameters: Vec<ShardValue>,
+    route_hints: RouteHints,
+    generated_key: Option<u64>,
 }
 
 impl<'a> ExplainPlanContext<'a> {
@@ -22,6 +41,11 @@ impl<'a> ExplainPlanContext<'a> {
             sql,
             statement,
             protocol,
+            max_lag_ms: None,
+            timeout_ms: None,
+            parameters: vec![],
+            route_hints: RouteHints::default(),
+            generated_key: None,
         }
     }
 
@@ -32,17 +56,201 @@ impl<'a> ExplainPlanContext<'a> {
     pub fn get_statement(&self) -> &'a Statement {
         self.statement
     }
+
+    pub fn get_max_lag_ms(&self) -> Option<u64> {
+        self.max_lag_ms
+    }
+
+    pub fn set_max_lag_ms(&mut self, max_lag_ms: Option<u64>) {
+        self.max_lag_ms = max_lag_ms;
+    }
+
+    pub fn get_timeout_ms(&self) -> Option<u64> {
+        self.timeout_ms
+    }
+
+    pub fn set_timeout_ms(&mut self, timeout_ms: Option<u64>) {
+        self.timeout_ms = timeout_ms;
+    }
+
+    /// Values of the statement's `?`s, sent with COM_STMT_EXECUTE.
+    pub fn get_parameters(&self) -> &Vec<ShardValue> {
+        &self.parameters
+    }
+
+    pub fn set_parameters(&mut self, parameters: Vec<ShardValue>) {
+        self.parameters = parameters;
+    }
+
+    /// The `/*+ MARTLET_* */` routing overrides of the SQL.
+    pub fn get_route_hints(&self) -> &RouteHints {
+        &self.route_hints
+    }
+
+    pub fn set_route_hints(&mut self, route_hints: RouteHints) {
+        self.route_hints = route_hints;
+    }
+
+    /// First key the proxy generated into the INSERT, reported as its `last_insert_id`.
+    pub fn get_generated_key(&self) -> Option<u64> {
+        self.generated_key
+    }
+
+    pub fn set_generated_key(&mut self, generated_key: Option<u64>) {
+        self.generated_key = generated_key;
+    }
 }
 
 pub trait Executor {
-    fn execute(&self) -> Option<Vec<Bytes>>;
+    fn execute(&self, session_ctx: &mut SessionContext) -> Option<Vec<Bytes>>;
+}
+
+/// One physical SQL of the plan and the segment group it runs on.
+pub struct PlanTask {
+    group: SegmentGroup,
+    sql: String,
+    /// The `VALUES` rows of a split INSERT the SQL keeps, `None` for all of them.
+    rows: Option<Vec<usize>>,
+    /// Indexes of the statement's `?` values the SQL takes, in order; `None` for all of them.
+    parameters: Option<Vec<usize>>,
+}
+
+impl PlanTask {
+    pub fn new(group: SegmentGroup, sql: String) -> Self {
+        PlanTask {
+            group,
+            sql,
+            rows: None,
+            parameters: None,
+        }
+    }
+
+    /// Task of a split INSERT, keeping the rows and the `?` values of their indexes.
+    pub fn with_rows(group: SegmentGroup, sql: String, rows: Vec<usize>, parameters: Vec<usize>) -> Self {
+        PlanTask {
+            group,
+            sql,
+            rows: Some(rows),
+            parameters: Some(parameters),
+        }
+    }
+
+    pub fn get_group(&self) -> SegmentGroup {
+        self.group
+    }
+
+    pub fn get_sql(&self) -> &String {
+        &self.sql
+    }
+
+    pub fn get_rows(&self) -> Option<&Vec<usize>> {
+        self.rows.as_ref()
+    }
+
+    pub fn get_parameters(&self) -> Option<&Vec<usize>> {
+        self.parameters.as_ref()
+    }
+}
+
+impl From<RouteUnit> for PlanTask {
+    fn from(unit: RouteUnit) -> Self {
+        PlanTask::new(unit.get_group(), unit.get_sql().clone())
+    }
+}
+
+/// Task of the unit: the statement with the tables renamed to their physical names in the
+/// unit and, for a split INSERT, only the unit's rows and their `?`s. A statement which cannot
+/// be rewritten is refused, the original would run on the wrong tables or all the rows.
+fn plan_task(route_ctx: &RouteContext, stmt_ctx: &SQLStatementContext, statement: &Statement, unit: &RouteUnit) -> Result<PlanTask, ProxyError> {
+    let physical = physical_tables(route_ctx, stmt_ctx, unit);
+    let split = unit.get_rows().map(|rows| split_insert(statement, rows));
+    let mut sql = String::new();
+    if !physical.is_empty() || split.is_some() {
+        if let Err(Error::General(e)) = split.as_ref().unwrap_or(statement).rewrite(&mut sql, &physical) {
+            println!("error on rewriting `{}` for {:?}; error = {:?}", unit.get_sql(), unit.get_group(), e);
+            return Err(ProxyError::Unsupported(format!("rewriting the statement for its segment tables ({})", e)));
+        }
+    }
+    if sql.is_empty() {
+        sql = unit.get_sql().clone();
+    }
+    Ok(match unit.get_rows() {
+        Some(rows) => {
+            let insert_rows = stmt_ctx.get_insert_rows();
+            let parameters = rows.iter().flat_map(|row| insert_rows[*row].get_parameters().clone()).collect();
+            PlanTask::with_rows(unit.get_group(), sql, rows.clone(), parameters)
+        }
+        None => PlanTask::new(unit.get_group(), sql),
+    })
 }
 
-pub struct PlanTask {}
+/// Functions folding the rows of a group into one, whose results over several segments would
+/// need merging again.
+const AGGREGATE_FUNCTIONS: [&str; 16] = [
+    "AVG", "BIT_AND", "BIT_OR", "BIT_XOR", "COUNT", "GROUP_CONCAT", "JSON_ARRAYAGG", "JSON_OBJECTAGG",
+    "MAX", "MIN", "STD", "STDDEV", "STDDEV_POP", "STDDEV_SAMP", "SUM", "VARIANCE",
+];
+
+/// Refuses a query planned into several tasks which the concatenation of their rows, all
+/// `merge_results` does, would answer wrongly: one aggregating, grouping, ordering, limiting or
+/// deduplicating its rows.
+fn check_mergeable(statement: &Statement, task_count: usize) -> Result<(), ProxyError> {
+    let clause = match statement {
+        Statement::Query(query) if task_count > 1 => unmergeable_clause(query),
+        _ => None,
+    };
+    match clause {
+        Some(clause) => Err(ProxyError::Unsupported(format!("{} over several segments", clause))),
+        None => Ok(()),
+    }
+}
+
+fn unmergeable_clause(query: &Query) -> Option<&'static str> {
+    if !query.order_by.is_empty() {
+        return Some("ORDER BY");
+    }
+    if query.limit.is_some() || query.offset.is_some() || query.fetch.is_some() {
+        return Some("LIMIT");
+    }
+    match &query.body {
+        SetExpr::Select(select) => unmergeable_select(select),
+        SetExpr::Query(query) => unmergeable_clause(query),
+        SetExpr::SetOperation { op: SetOperator::Union, all: true, .. } => None,
+        SetExpr::SetOperation { .. } => Some("UNION, EXCEPT or INTERSECT without ALL"),
+        _ => None,
+    }
+}
+
+fn unmergeable_select(select: &Select) -> Option<&'static str> {
+    if select.distinct {
+        return Some("DISTINCT");
+    }
+    if select.top.is_some() {
+        return Some("LIMIT");
+    }
+    if !select.group_by.is_empty() || select.having.is_some() {
+        return Some("GROUP BY");
+    }
+    let mut projection_ctx = SQLStatementContext::Select(SelectStatementContext::new());
+    for item in &select.projection {
+        if item.analyse(&mut projection_ctx).is_err() {
+            return Some("a projection which cannot be analysed");
+        }
+    }
+    if projection_ctx.get_functions().iter().any(|function| AGGREGATE_FUNCTIONS.contains(&function.as_str())) {
+        return Some("aggregate functions");
+    }
+    None
+}
 
 pub struct ExplainPlan<'a> {
     ctx: &'a ExplainPlanContext<'a>,
     tasks: Vec<PlanTask>,
+    /// Error packet of a statement which cannot be routed.
+    error: Option<Bytes>,
+    key_move: Option<KeyMove>,
+    /// Physical name of the moved rows' table on each segment group of the plan.
+    move_tables: HashMap<SegmentGroup, String>,
 }
 
 impl<'a> ExplainPlan<'a> {
@@ -50,21 +258,262 @@ impl<'a> ExplainPlan<'a> {
         ExplainPlan {
             ctx: ctx,
             tasks: vec![],
+            error: None,
+            key_move: None,
+            move_tables: HashMap::new(),
+        }
+    }
+
+    /// Routes the statement into tasks. Statements ending or marking a transaction also go to
+    /// every segment group the session's transaction has touched.
+    pub fn gen(&mut self, session_ctx: &SessionContext) {
+        let sql = self.ctx.get_sql();
+        let statement = self.ctx.get_statement();
+        let mut stmt_ctx = SQLStatementContext::new(statement);
+        self.tasks = match statement.analyse(&mut stmt_ctx) {
+            Ok(_) => {
+                let schema = statement_schema(&Cluster::current(), &stmt_ctx, session_ctx.get_database().as_str());
+                let route_ctx = match schema {
+                    Ok(schema) => RouteContext::current_of(schema),
+                    Err(e) => {
+                        self.error = Some(error_payload(e));
+                        return;
+                    }
+                };
+                let routed = route(&route_ctx, &stmt_ctx, sql, self.ctx.get_parameters(), session_ctx.get_pinned_groups().as_slice(), self.ctx.get_route_hints())
+                    .and_then(|result| check_mergeable(statement, result.get_units().len()).map(|_| result));
+                match routed {
+                    Ok(result) => {
+                        if let Some(key_move) = result.get_key_move() {
+                            let table = logical_table_name(key_move.get_table()).to_lowercase();
+                            for unit in result.get_units() {
+                                let physical = physical_tables(&route_ctx, &stmt_ctx, unit);
+                                let move_table = physical.get(&table).cloned().unwrap_or_else(|| key_move.get_table().clone());
+                                self.move_tables.insert(unit.get_group(), move_table);
+                            }
+                            self.key_move = Some(key_move.clone());
+                        }
+                        let tasks: Result<Vec<PlanTask>, ProxyError> = result.get_units().iter()
+                            .map(|unit| plan_task(&route_ctx, &stmt_ctx, statement, unit))
+                            .collect();
+                        match tasks {
+                            Ok(tasks) => tasks,
+                            Err(e) => {
+                                self.error = Some(error_payload(e));
+                                vec![]
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        self.error = Some(error_payload(e));
+                        vec![]
+                    }
+                }
+            }
+            Err(_) => vec![PlanTask::new(SegmentGroup::Meta, sql.to_string())],
+        };
+        let all_groups = ends_transaction(statement, session_ctx.get_session_state()) || match statement {
+            Statement::Rollback { .. } | Statement::Savepoint { .. } | Statement::Release { .. } => true,
+            _ => false,
+        };
+        if all_groups {
+            for group in session_ctx.get_pinned_groups() {
+                if !self.tasks.iter().any(|task| task.group == group) {
+                    self.tasks.push(PlanTask::new(group, sql.to_string()));
+                }
+            }
         }
     }
 
-    pub fn gen(&self) {}
+    /// Error packet to answer with instead of running the plan.
+    pub fn get_error(&self) -> Option<&Bytes> {
+        self.error.as_ref()
+    }
 
     pub fn ctx(&self) -> &'a ExplainPlanContext<'a> {
         self.ctx
     }
+
+    pub fn tasks(&self) -> &Vec<PlanTask> {
+        &self.tasks
+    }
+
+    /// The rows the UPDATE moves to the segment of their new sharding keys, with the physical
+    /// name of their table on each segment group.
+    pub fn get_key_move(&self) -> Option<(&KeyMove, &HashMap<SegmentGroup, String>)> {
+        self.key_move.as_ref().map(|key_move| (key_move, &self.move_tables))
+    }
 }
 
 impl<'a> Executor for ExplainPlan<'a> {
-    fn execute(&self) -> Option<Vec<Bytes>> {
+    fn execute(&self, session_ctx: &mut SessionContext) -> Option<Vec<Bytes>> {
+        if let Some(error) = &self.error {
+            return Some(vec![error.clone()]);
+        }
         match self.ctx.protocol {
-            TBProtocol::Text => { text_query(&self) }
-            TBProtocol::Binary => { bin_query(&self) }
+            TBProtocol::Text => { text_query(&self, session_ctx) }
+            TBProtocol::Binary => { bin_query(&self, session_ctx) }
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::discovery::{Cluster, SegmentGroup};
+    use crate::error::ProxyError;
+    use crate::handler::mysql::explainplan::{check_mergeable, plan_task};
+    use crate::handler::parser::sql::analyse::SQLAnalyse;
+    use crate::handler::parser::sql::mysql::{parser, RouteHints};
+    use crate::handler::parser::sql::route::hash::hash_key;
+    use crate::handler::parser::sql::route::{route, RouteContext};
+    use crate::handler::parser::sql::route::value::ShardValue;
+    use crate::handler::parser::sql::SQLStatementContext;
+
+    const CLUSTER: &str = r#"
+name: martlet
+segments:
+  meta_segment:
+    primary: { id: 0, url: "jdbc:mysql://localhost:3306/martlet", username: root, password: root }
+    mirrors: [ ]
+  data_segments:
+    100:
+      primary: { id: 0, url: "jdbc:mysql://localhost:3306/martlet_100", username: root, password: root }
+      mirrors: [ ]
+    200:
+      primary: { id: 0, url: "jdbc:mysql://localhost:3306/martlet_200", username: root, password: root }
+      mirrors: [ ]
+dis_rules:
+  distributed_tables:
+    t_order:
+      dis_keys: [ user_id ]
+      dis_algorithm: { dis_type: HASH, dis_expression: "" }
+      dis_relatives: [ t_order_item ]
+      physical_tables: { 100: [ t_order_0, t_order_1 ], 200: [ t_order_0, t_order_1 ] }
+    t_order_item:
+      dis_keys: [ order_user_id ]
+      dis_relatives: [ t_order ]
+      physical_tables: { 100: [ t_order_item_0, t_order_item_1 ], 200: [ t_order_item_0, t_order_item_1 ] }
+    t_user:
+      dis_keys: [ id ]
+      dis_algorithm: { dis_type: HASH, dis_expression: "" }
+      physical_tables: { 100: [ t_user_0, t_user_1 ] }
+  replicated_tables: [ ]
+"#;
+
+    /// Segment group and SQL of every task the statement is planned into, in plan order.
+    fn plan(route_ctx: &RouteContext, sql: &str) -> Vec<(SegmentGroup, String)> {
+        try_plan(route_ctx, sql, &[]).unwrap()
+    }
+
+    fn try_plan(route_ctx: &RouteContext, sql: &str, parameters: &[ShardValue]) -> Result<Vec<(SegmentGroup, String)>, ProxyError> {
+        let statement = parser(sql.to_string()).pop().unwrap();
+        let mut stmt_ctx = SQLStatementContext::new(&statement);
+        statement.analyse(&mut stmt_ctx).unwrap();
+        let result = route(route_ctx, &stmt_ctx, sql, parameters, &[], &RouteHints::default())?;
+        check_mergeable(&statement, result.get_units().len())?;
+        result.get_units().iter()
+            .map(|unit| plan_task(route_ctx, &stmt_ctx, &statement, unit))
+            .map(|task| task.map(|task| (task.get_group(), task.get_sql().clone())))
+            .collect()
+    }
+
+    #[test]
+    fn test_plan_physical_tables() {
+        let route_ctx = RouteContext::new(Arc::new(Cluster::from_str(CLUSTER)));
+        let algorithm = route_ctx.get_algorithm("t_order").unwrap();
+        let id = algorithm.shard(&[ShardValue::Int(42)]).unwrap();
+        let group = SegmentGroup::Data(id);
+        let index = hash_key(&[ShardValue::Int(42)]) % 2;
+
+        assert_eq!(plan(&route_ctx, "SELECT o.id FROM t_order AS o WHERE o.user_id = 42"),
+                   vec![(group, format!("SELECT o.id FROM t_order_{} AS o WHERE o.user_id = 42", index))]);
+        assert_eq!(plan(&route_ctx, "SELECT t_order.id FROM t_order WHERE t_order.user_id = 42"),
+                   vec![(group, format!("SELECT t_order_{0}.id FROM t_order_{0} WHERE t_order_{0}.user_id = 42", index))]);
+        assert_eq!(plan(&route_ctx, "SELECT * FROM martlet.t_order WHERE user_id = 42"),
+                   vec![(group, format!("SELECT * FROM martlet_{}.t_order_{} WHERE user_id = 42", id, index))]);
+        assert_eq!(plan(&route_ctx, "SELECT * FROM t_order AS o JOIN t_order_item AS i ON o.user_id = i.order_user_id WHERE o.user_id = 42"),
+                   vec![(group, format!("SELECT * FROM t_order_{0} AS o JOIN t_order_item_{0} AS i ON o.user_id = i.order_user_id WHERE o.user_id = 42", index))]);
+
+        let every_table = |sql: &str, table: &str| -> Vec<(SegmentGroup, String)> {
+            let mut tasks = Vec::new();
+            for id in &[100, 200] {
+                for index in 0..2 {
+                    tasks.push((SegmentGroup::Data(*id), sql.replace(table, format!("{}_{}", table, index).as_str())));
+                }
+            }
+            tasks
+        };
+        assert_eq!(plan(&route_ctx, "SELECT * FROM t_order"), every_table("SELECT * FROM t_order", "t_order"));
+        assert_eq!(plan(&route_ctx, "DROP TABLE t_order"), every_table("DROP TABLE t_order", "t_order"));
+        assert_eq!(plan(&route_ctx, "CREATE TABLE t_order (id BIGINT, user_id BIGINT)"),
+                   every_table("CREATE TABLE t_order (id BIGINT, user_id BIGINT)", "t_order"));
+
+        // t_user is split on segment 100 only.
+        let tasks = plan(&route_ctx, "TRUNCATE TABLE t_user");
+        assert_eq!(tasks.len(), 3);
+        assert!(tasks.contains(&(SegmentGroup::Data(100), String::from("TRUNCATE TABLE t_user_0"))));
+        assert!(tasks.contains(&(SegmentGroup::Data(200), String::from("TRUNCATE TABLE t_user"))));
+
+        // Tables not joined on their keys cannot pair their physical tables.
+        let statement = parser(String::from("SELECT * FROM t_order AS o JOIN t_user AS u ON o.user_id = u.id")).pop().unwrap();
+        let mut stmt_ctx = SQLStatementContext::new(&statement);
+        statement.analyse(&mut stmt_ctx).unwrap();
+        assert!(route(&route_ctx, &stmt_ctx, "", &[], &[], &RouteHints::default()).is_err());
+    }
+
+    #[test]
+    fn test_plan_prepared_read() {
+        let route_ctx = RouteContext::new(Arc::new(Cluster::from_str(CLUSTER)));
+        let algorithm = route_ctx.get_algorithm("t_order").unwrap();
+        let sql = "SELECT id, status FROM t_order WHERE user_id = ?";
+        // The values of two executions of the statement, routed to physical tables of their own.
+        let user_id = (1..100).find(|user_id| hash_key(&[ShardValue::Int(*user_id)]) % 2 == 0).unwrap();
+        let other = (1..100).find(|user_id| hash_key(&[ShardValue::Int(*user_id)]) % 2 == 1).unwrap();
+        for (user_id, index) in &[(user_id, 0), (other, 1)] {
+            let group = SegmentGroup::Data(algorithm.shard(&[ShardValue::Int(*user_id)]).unwrap());
+            assert_eq!(try_plan(&route_ctx, sql, &[ShardValue::Int(*user_id)]).unwrap(),
+                       vec![(group, format!("SELECT id, status FROM t_order_{} WHERE user_id = ?", index))]);
+        }
+    }
+
+    #[test]
+    fn test_plan_unmergeable() {
+        let route_ctx = RouteContext::new(Arc::new(Cluster::from_str(CLUSTER)));
+        for sql in &["SELECT COUNT(*) FROM t_order",
+                     "SELECT user_id, SUM(amount) AS total FROM t_order GROUP BY user_id",
+                     "SELECT * FROM t_order ORDER BY id",
+                     "SELECT * FROM t_order LIMIT 10",
+                     "SELECT DISTINCT status FROM t_order",
+                     "SELECT id FROM t_order UNION SELECT user_id FROM t_order"] {
+            match try_plan(&route_ctx, sql, &[]) {
+                Err(ProxyError::Unsupported(_)) => {}
+                _ => panic!("`{}` is planned over several segments", sql),
+            }
+        }
+        // On one physical table the backend aggregates, orders and limits by itself.
+        assert_eq!(plan(&route_ctx, "SELECT COUNT(*) FROM t_order WHERE user_id = 42 ORDER BY id LIMIT 10").len(), 1);
+        assert_eq!(plan(&route_ctx, "SELECT id, UPPER(status) FROM t_order").len(), 4);
+    }
+
+    #[test]
+    fn test_plan_physical_insert() {
+        let route_ctx = RouteContext::new(Arc::new(Cluster::from_str(CLUSTER)));
+        let algorithm = route_ctx.get_algorithm("t_order").unwrap();
+        let mut expected: Vec<(SegmentGroup, String)> = Vec::new();
+        for id in &[100, 200] {
+            for index in 0..2 {
+                let rows: Vec<String> = (1..=8)
+                    .filter(|user_id| algorithm.shard(&[ShardValue::Int(*user_id)]) == Some(*id))
+                    .filter(|user_id| hash_key(&[ShardValue::Int(*user_id)]) % 2 == index)
+                    .map(|user_id| format!("({}, 0)", user_id))
+                    .collect();
+                if !rows.is_empty() {
+                    expected.push((SegmentGroup::Data(*id), format!("INSERT INTO t_order_{} (user_id, status) VALUES {}", index, rows.join(", "))));
+                }
+            }
         }
+        let rows: Vec<String> = (1..=8).map(|user_id| format!("({}, 0)", user_id)).collect();
+        assert_eq!(plan(&route_ctx, format!("INSERT INTO t_order (user_id, status) VALUES {}", rows.join(", ")).as_str()), expected);
     }
-}
\ No newline at end of file
+}
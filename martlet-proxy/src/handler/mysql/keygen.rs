@@ -0,0 +1,323 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use mysql::prelude::Queryable;
+use sqlparser::ast::{Expr, Ident, SelectItem, SetExpr, Statement, Value};
+
+use martlet_common::config::config::MeshConfig;
+
+use crate::discovery::{Cluster, KeyGenerator, KeyType, SegmentGroup};
+use crate::discovery::health;
+use crate::error::ProxyError;
+use crate::handler::mysql::rdbc::BackendConn;
+use crate::handler::parser::sql::condition::InsertRow;
+use crate::handler::parser::sql::mysql::parser;
+use crate::handler::parser::sql::route::logical_table_name;
+
+/// Snowflake ids count milliseconds from 2021-01-01T00:00:00Z.
+const SNOWFLAKE_EPOCH_MS: u64 = 1_609_459_200_000;
+const WORKER_ID_BITS: u64 = 10;
+const SEQUENCE_BITS: u64 = 12;
+const SEQUENCE_MASK: u64 = (1 << SEQUENCE_BITS) - 1;
+
+lazy_static! {
+    static ref SNOWFLAKE: Option<Snowflake> = MeshConfig::get_worker_id().map(Snowflake::new);
+    /// Ids of the SEGMENT tables taken from the sequence table and not handed out yet.
+    static ref SEQUENCES: Mutex<HashMap<String, Arc<Mutex<IdBlock>>>> = Mutex::new(HashMap::new());
+    static ref SEQUENCE_TABLE_CREATED: AtomicBool = AtomicBool::new(false);
+    /// A `?`, as the parser makes it, for the generated keys bound to parameters.
+    static ref PARAMETER_MARK: Option<Expr> = match parser(String::from("SELECT ?")).pop() {
+        Some(Statement::Query(query)) => match query.body {
+            SetExpr::Select(select) => match select.projection.first() {
+                Some(SelectItem::UnnamedExpr(expr)) => Some(expr.clone()),
+                _ => None,
+            },
+            _ => None,
+        },
+        _ => None,
+    };
+}
+
+/// Time ordered 64 bit ids: 41 bits of milliseconds, 10 of the worker id and 12 of sequence.
+///
+/// Ids stay unique when the clock is set back or more than 4096 are asked for in one
+/// millisecond: the generator then keeps counting from the last millisecond it used.
+pub struct Snowflake {
+    worker_id: u64,
+    /// Millisecond and sequence of the last id.
+    last: Mutex<(u64, u64)>,
+}
+
+impl Snowflake {
+    /// Generator of the node, whose `worker_id` the node config checked to be below `MAX_WORKERS`.
+    pub fn new(worker_id: u32) -> Self {
+        Snowflake {
+            worker_id: worker_id as u64,
+            last: Mutex::new((0, 0)),
+        }
+    }
+
+    pub fn next_id(&self) -> u64 {
+        let now_ms = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0);
+        let mut last = self.last.lock().unwrap();
+        if now_ms > last.0 {
+            *last = (now_ms, 0);
+        } else if last.1 == SEQUENCE_MASK {
+            *last = (last.0 + 1, 0);
+        } else {
+            last.1 = last.1 + 1;
+        }
+        (last.0.saturating_sub(SNOWFLAKE_EPOCH_MS) << (WORKER_ID_BITS + SEQUENCE_BITS)) | (self.worker_id << SEQUENCE_BITS) | last.1
+    }
+}
+
+/// Ids `next..end` a proxy node took from the sequence table.
+#[derive(Debug, Default, PartialEq)]
+struct IdBlock {
+    next: u64,
+    end: u64,
+}
+
+impl IdBlock {
+    /// Next id of the block, which `take` replaces with a new one once it is used up.
+    fn next_id<F>(&mut self, take: F) -> Result<u64, ProxyError>
+        where F: FnOnce() -> Result<IdBlock, ProxyError> {
+        if self.next >= self.end {
+            *self = take()?;
+        }
+        let id = self.next;
+        self.next = self.next + 1;
+        Ok(id)
+    }
+}
+
+/// Takes the table's next `step` ids from `martlet_sequence` on the meta segment.
+///
+/// `LAST_INSERT_ID(expr)` hands the advanced value back on the same connection, so every
+/// node takes a block of its own without locking the row across statements.
+fn take_ids(table: &str, step: u64) -> Result<IdBlock, ProxyError> {
+    let cluster = Cluster::current();
+    let primary = health::primary_of(&cluster, SegmentGroup::Meta).cloned().unwrap_or_default();
+    let mut conn = BackendConn::open(SegmentGroup::Meta, &primary)?;
+    if !SEQUENCE_TABLE_CREATED.load(Ordering::SeqCst) {
+        let created = conn.query_drop("CREATE TABLE IF NOT EXISTS martlet_sequence (name VARCHAR(64) PRIMARY KEY, next_id BIGINT UNSIGNED NOT NULL)");
+        conn.record(&created);
+        created?;
+        SEQUENCE_TABLE_CREATED.store(true, Ordering::SeqCst);
+    }
+    let taken = conn.exec_drop("INSERT IGNORE INTO martlet_sequence (name, next_id) VALUES (?, 1)", (table,))
+        .and_then(|_| conn.exec_drop("UPDATE martlet_sequence SET next_id = LAST_INSERT_ID(next_id + ?) WHERE name = ?", (step, table)))
+        .and_then(|_| conn.query_first::<u64, _>("SELECT LAST_INSERT_ID()"));
+    conn.record(&taken);
+    let end = taken?.unwrap_or(0);
+    Ok(IdBlock {
+        next: end.saturating_sub(step),
+        end,
+    })
+}
+
+/// Next generated key of the table.
+pub fn next_id(table: &str, key_generator: &KeyGenerator) -> Result<u64, ProxyError> {
+    match key_generator.get_key_type() {
+        KeyType::SNOWFLAKE => SNOWFLAKE.as_ref()
+            .map(Snowflake::next_id)
+            .ok_or_else(|| ProxyError::Unsupported(format!("SNOWFLAKE keys of {} without a worker_id of the node", table))),
+        KeyType::SEGMENT => {
+            let block = SEQUENCES.lock().unwrap()
+                .entry(table.to_lowercase())
+                .or_insert_with(|| Arc::new(Mutex::new(IdBlock::default())))
+                .clone();
+            let mut block = block.lock().unwrap();
+            block.next_id(|| take_ids(table.to_lowercase().as_str(), key_generator.get_step()))
+        }
+    }
+}
+
+/// How the generated keys go into the INSERT: as literals, or as `?`s bound to them, which
+/// keeps the SQL of a prepared statement the same from one execution to the next.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyBinding {
+    Literal,
+    Parameter,
+}
+
+/// The keys generated into an INSERT, first row first.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GeneratedKeys {
+    ids: Vec<u64>,
+    /// Index among the statement's `?`s of each id's, empty when they are literals.
+    parameters: Vec<usize>,
+}
+
+impl GeneratedKeys {
+    /// Key of the first row, the `last_insert_id` the client sees.
+    pub fn get_first_id(&self) -> Option<u64> {
+        self.ids.first().cloned()
+    }
+
+    /// Adds the ids bound to `?`s to the values of the client's `?`s, each at its index.
+    pub fn bind<T, F>(&self, values: &mut Vec<T>, value_of: F) where F: Fn(u64) -> T {
+        for (index, id) in self.parameters.iter().zip(&self.ids) {
+            values.insert((*index).min(values.len()), value_of(*id));
+        }
+    }
+}
+
+/// Adds the generated key column to an `INSERT ... VALUES` into a table with a key generator
+/// which leaves the column out, a new key in every row, so the rows are routed on them.
+/// `database` is the session's, it picks the rules of an unqualified table.
+pub fn fill_generated_keys(statement: &mut Statement, database: &str, binding: KeyBinding) -> Result<Option<GeneratedKeys>, ProxyError> {
+    fill_keys(statement, &Cluster::current(), database, binding, next_id)
+}
+
+/// `fill_generated_keys` over the cluster, with the ids of `next_id`.
+fn fill_keys<F>(statement: &mut Statement, cluster: &Cluster, database: &str, binding: KeyBinding, mut next_id: F) -> Result<Option<GeneratedKeys>, ProxyError>
+    where F: FnMut(&str, &KeyGenerator) -> Result<u64, ProxyError> {
+    let (table_name, columns, source) = match statement {
+        Statement::Insert { table_name, columns, source, .. } => (table_name, columns, source),
+        _ => return Ok(None),
+    };
+    let qualified = table_name.to_string();
+    let table = logical_table_name(qualified.as_str());
+    let schema = match qualified.rsplitn(2, '.').nth(1) {
+        Some(qualifier) => qualifier.trim_matches(|c| c == '`' || c == '"'),
+        None => database,
+    };
+    let dis_rules = cluster.get_schema_dis_rules(Some(schema));
+    let key_generator = match dis_rules.get_distributed_table(table.as_str()).and_then(|dis_table| dis_table.get_key_generator()) {
+        Some(key_generator) => key_generator,
+        None => return Ok(None),
+    };
+    // Values without a column list are positional, the key cannot be added to them.
+    if columns.is_empty() || columns.iter().any(|column| column.value.eq_ignore_ascii_case(key_generator.get_column())) {
+        return Ok(None);
+    }
+    let rows = match &mut source.body {
+        SetExpr::Values(values) => &mut values.0,
+        _ => return Ok(None),
+    };
+    let mark = match binding {
+        KeyBinding::Literal => None,
+        KeyBinding::Parameter => Some(PARAMETER_MARK.clone()
+            .ok_or_else(|| ProxyError::Unsupported(String::from("generated keys bound to parameters")))?),
+    };
+    let mut generated = GeneratedKeys {
+        ids: Vec::with_capacity(rows.len()),
+        parameters: vec![],
+    };
+    // The `?` of a row's key follows the `?`s of the row and of the rows before it.
+    let mut parameter_offset = 0;
+    for row in rows.iter_mut() {
+        let id = next_id(table.as_str(), key_generator)?;
+        generated.ids.push(id);
+        match &mark {
+            Some(mark) => {
+                parameter_offset = InsertRow::extract(row.as_slice(), parameter_offset).get_parameters().end;
+                generated.parameters.push(parameter_offset);
+                parameter_offset = parameter_offset + 1;
+                row.push(mark.clone());
+            }
+            None => row.push(Expr::Value(Value::Number(id.to_string(), false))),
+        }
+    }
+    columns.push(Ident::new(key_generator.get_column().as_str()));
+    Ok(Some(generated))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use crate::discovery::Cluster;
+    use crate::error::ProxyError;
+    use crate::handler::mysql::keygen::{fill_keys, IdBlock, KeyBinding, Snowflake};
+    use crate::handler::parser::sql::mysql::parser;
+
+    const CLUSTER: &str = r#"
+name: martlet
+segments:
+  meta_segment:
+    primary: { id: 0, url: "jdbc:mysql://localhost:3306/martlet", username: root, password: root }
+    mirrors: [ ]
+  data_segments:
+    100:
+      primary: { id: 0, url: "jdbc:mysql://localhost:3306/martlet_100", username: root, password: root }
+      mirrors: [ ]
+dis_rules:
+  distributed_tables:
+    t_order:
+      dis_keys: [ user_id ]
+      dis_algorithm: { dis_type: HASH, dis_expression: "" }
+      dis_relatives: [ ]
+      key_generator: { column: order_id, key_type: SEGMENT, step: 100 }
+  replicated_tables: [ ]
+"#;
+
+    #[test]
+    fn test_snowflake() {
+        let snowflake = Snowflake::new(3);
+        let mut last = 0;
+        for _ in 0..10000 {
+            let id = snowflake.next_id();
+            assert!(id > last);
+            assert_eq!(3, (id >> 12) & 0x3ff);
+            last = id;
+        }
+        assert_ne!(Snowflake::new(4).next_id() >> 12 & 0x3ff, Snowflake::new(3).next_id() >> 12 & 0x3ff);
+    }
+
+    #[test]
+    fn test_fill_generated_keys() {
+        let cluster = Cluster::from_str(CLUSTER);
+        let fill = |sql: &str, binding: KeyBinding| {
+            let next = Cell::new(7);
+            let mut statement = parser(sql.to_string()).pop().unwrap();
+            let generated = fill_keys(&mut statement, &cluster, "martlet", binding, |_, _| {
+                next.set(next.get() + 1);
+                Ok(next.get() - 1)
+            }).unwrap();
+            (statement.to_string(), generated)
+        };
+
+        let (sql, generated) = fill("INSERT INTO t_order (user_id, status) VALUES (1, 0), (2, 0)", KeyBinding::Literal);
+        assert_eq!("INSERT INTO t_order (user_id, status, order_id) VALUES (1, 0, 7), (2, 0, 8)", sql);
+        assert_eq!(Some(7), generated.unwrap().get_first_id());
+
+        let (sql, generated) = fill("INSERT INTO martlet.t_order (user_id, status) VALUES (?, 0), (?, ?)", KeyBinding::Parameter);
+        assert_eq!("INSERT INTO martlet.t_order (user_id, status, order_id) VALUES (?, 0, ?), (?, ?, ?)", sql);
+        let generated = generated.unwrap();
+        let mut values = vec![10, 20, 30];
+        generated.bind(&mut values, |id| id * 100);
+        assert_eq!(vec![10, 700, 20, 30, 800], values);
+
+        assert_eq!(None, fill("INSERT INTO t_order (user_id, order_id) VALUES (1, 5)", KeyBinding::Literal).1);
+        assert_eq!(None, fill("INSERT INTO t_order VALUES (1, 0)", KeyBinding::Literal).1);
+        assert_eq!(None, fill("INSERT INTO t_user (name) VALUES ('a')", KeyBinding::Literal).1);
+        assert_eq!(None, fill("UPDATE t_order SET status = 1", KeyBinding::Literal).1);
+    }
+
+    #[test]
+    fn test_id_block() {
+        let taken = Cell::new(0);
+        let take = || -> Result<IdBlock, ProxyError> {
+            taken.set(taken.get() + 1);
+            Ok(IdBlock {
+                next: taken.get() * 100 - 100 + 1,
+                end: taken.get() * 100 + 1,
+            })
+        };
+        let mut block = IdBlock::default();
+        let ids: Vec<u64> = (0..250).map(|_| block.next_id(take).unwrap()).collect();
+        assert_eq!((1..=250).collect::<Vec<u64>>(), ids);
+        assert_eq!(3, taken.get());
+        assert_eq!(IdBlock { next: 251, end: 301 }, block);
+
+        // A failed take leaves the used up block as it is, the next id takes again.
+        let mut block = IdBlock { next: 5, end: 5 };
+        assert!(block.next_id(|| Err(ProxyError::Unsupported(String::from("down")))).is_err());
+        assert_eq!(IdBlock { next: 5, end: 5 }, block);
+        assert_eq!(301, block.next_id(take).unwrap());
+    }
+}
@@ -1,6 +1,10 @@
 use bytes::Bytes;
 
+use crate::discovery::{Cluster, SegmentGroup};
+use crate::discovery::health;
+use crate::error::ProxyError;
 use crate::handler::mysql::binary::{ComStmtCloseHandler, ComStmtExecuteHandler, ComStmtPrepareHandler, ComStmtResetHandler};
+use crate::handler::mysql::rdbc::{error_payload, BackendConn};
 use crate::handler::mysql::text::ComQueryHandler;
 use crate::protocol::{CommandPacketType, DatabasePacket, PacketPayload};
 use crate::protocol::mysql::constant::{MySQLAuthenticationMethod, MySQLCapabilityFlag, MySQLCommandPacketType, MySQLConnectionPhase};
@@ -10,7 +14,10 @@ use crate::session::mysql::SessionContext;
 pub mod text;
 pub mod binary;
 pub mod explainplan;
+pub mod keygen;
 pub mod rdbc;
+pub mod retry;
+pub mod xa;
 
 pub trait CommandHandler<P, Session> {
     fn handle(command_packet_header: Option<MySQLPacketHeader>, command_packet: Option<P>, session_ctx: &mut Session) -> Option<Vec<Bytes>>;
@@ -121,6 +128,7 @@ pub struct ComQuitHandler {}
 
 impl CommandHandler<MySQLPacketPayload, SessionContext> for ComQuitHandler {
     fn handle(command_packet_header: Option<MySQLPacketHeader>, command_packet: Option<MySQLPacketPayload>, session_ctx: &mut SessionContext) -> Option<Vec<Bytes>> {
+        session_ctx.release_pinned_conns();
         let mut ok_packet = MySQLOKPacket::new(1, 0, 0);
         let mut ok_payload = MySQLPacketPayload::new();
         let ok_payload = DatabasePacket::encode(&mut ok_packet, &mut ok_payload);
@@ -148,6 +156,22 @@ impl CommandHandler<MySQLPacketPayload, SessionContext> for ComInitDbHandler {
         let mut command_payload = command_packet_payload.unwrap();
         let mut query_packet = MySQLComInitDbPacket::new(command_packet_type);
         let command_packet = DatabasePacket::decode(&mut query_packet, &command_packet_header, &mut command_payload, session_ctx);
+        let schema = String::from_utf8_lossy(command_packet.get_schema().as_slice()).to_string();
+
+        // Switch on a primary first, so an unknown schema is answered by the backend.
+        let mut session_state = session_ctx.get_session_state().clone();
+        session_state.set_database(schema.clone());
+        let primary = health::primary_of(&Cluster::current(), SegmentGroup::Meta).cloned().unwrap_or_default();
+        let switched = BackendConn::open(SegmentGroup::Meta, &primary)
+            .and_then(|mut conn| {
+                let replayed = conn.replay(&session_state);
+                conn.record(&replayed);
+                replayed.map_err(ProxyError::from)
+            });
+        if let Err(e) = switched {
+            return Some(vec![error_payload(e)]);
+        }
+        session_ctx.set_database(schema);
 
         let mut ok_packet = MySQLOKPacket::new(1, 0, 0);
         let mut ok_payload = MySQLPacketPayload::new();
@@ -0,0 +1,101 @@
+use std::thread;
+use std::time::Duration;
+
+use rand::Rng;
+use sqlparser::ast::{Expr, Statement, UnaryOperator};
+
+use crate::discovery::RetryConfig;
+use crate::error::ProxyError;
+use crate::session::mysql::SessionContext;
+
+/// Errors which may pass when the statement runs again: deadlocks, lock wait timeouts and
+/// connections lost, e.g. while a segment fails over.
+pub fn is_transient(e: &ProxyError) -> bool {
+    match e {
+        ProxyError::Backend(e) => match *e {
+            mysql::error::Error::MySqlError(ref err) => err.code == 1213 || err.code == 1205,
+            mysql::error::Error::IoError(_) => true,
+            mysql::error::Error::DriverError(mysql::DriverError::ConnectionClosed) => true,
+            mysql::error::Error::DriverError(mysql::DriverError::CouldNotConnect(_)) => true,
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// Whether running the statement twice cannot change the outcome: reads in autocommit mode,
+/// and outside transactions `DELETE`s and `UPDATE`s which only assign constants without a `LIMIT`.
+pub fn is_safe_to_retry(statement: &Statement, session_ctx: &SessionContext) -> bool {
+    if session_ctx.is_transactional() {
+        return false;
+    }
+    match statement {
+        Statement::Query(_) => true,
+        // The parser has no `DELETE ... ORDER BY ... LIMIT`, which is not idempotent. Naming every
+        // field stops this from compiling once it gains one, so that case gets the `limit` check.
+        Statement::Delete { table_name: _, selection: _ } => true,
+        Statement::Update { assignments, limit, .. } => {
+            limit.is_none() && assignments.iter().all(|assignment| is_constant(&assignment.value))
+        }
+        _ => false,
+    }
+}
+
+fn is_constant(expr: &Expr) -> bool {
+    match expr {
+        Expr::Value(_) => true,
+        Expr::ParameterMark(_) => true,
+        Expr::Nested(expr) => is_constant(expr),
+        Expr::UnaryOp { op: UnaryOperator::Minus, expr } | Expr::UnaryOp { op: UnaryOperator::Plus, expr } => is_constant(expr),
+        _ => false,
+    }
+}
+
+/// Sleeps before the given retry (1 for the first), exponentially with jitter.
+pub fn backoff(config: &RetryConfig, retry: u32) {
+    let exponential = config.get_backoff_ms().saturating_mul(1u64 << (retry - 1).min(16));
+    let backoff_ms = exponential.min(config.get_max_backoff_ms());
+    let jitter_ms = rand::thread_rng().gen_range(0..=backoff_ms / 2);
+    thread::sleep(Duration::from_millis(backoff_ms / 2 + jitter_ms));
+}
+
+/// Runs the attempt until it succeeds, fails with a non transient error, the statement is not
+/// safe to retry or the configured attempts are used up.
+pub fn with_retry<T, F>(config: &RetryConfig, statement: &Statement, session_ctx: &mut SessionContext, mut attempt: F) -> Result<T, ProxyError>
+    where F: FnMut(&mut SessionContext) -> Result<T, ProxyError> {
+    let retryable = is_safe_to_retry(statement, session_ctx);
+    let mut attempts = 1;
+    loop {
+        match attempt(session_ctx) {
+            Err(e) if retryable && attempts < config.get_max_attempts() && is_transient(&e) => {
+                println!("retry: attempt {} of `{}` failed; error = {}", attempts, statement, e);
+                backoff(config, attempts);
+                attempts = attempts + 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::handler::mysql::retry::is_safe_to_retry;
+    use crate::handler::parser::sql::mysql::parser;
+    use crate::session::mysql::SessionContext;
+
+    #[test]
+    fn test_safe_to_retry() {
+        let mut session_ctx = SessionContext::new(1);
+        let safe = |sql: &str, session_ctx: &SessionContext| {
+            is_safe_to_retry(&parser(sql.to_string()).pop().unwrap(), session_ctx)
+        };
+        assert!(safe("SELECT * FROM t_order WHERE id = 1", &session_ctx));
+        assert!(safe("UPDATE t_order SET status = 2, note = ? WHERE id = 1", &session_ctx));
+        assert!(safe("DELETE FROM t_order WHERE id = 1", &session_ctx));
+        assert!(!safe("UPDATE t_order SET amount = amount + 1 WHERE id = 1", &session_ctx));
+        assert!(!safe("INSERT INTO t_order (id) VALUES (1)", &session_ctx));
+
+        session_ctx.track_transaction(&parser("BEGIN".to_string()).pop().unwrap());
+        assert!(!safe("SELECT * FROM t_order WHERE id = 1", &session_ctx));
+    }
+}
@@ -1,12 +1,18 @@
 use bytes::Bytes;
+use sqlparser::ast::{SetVariableValue, Statement, Value};
 
 use crate::handler::mysql::CommandHandler;
 use crate::handler::mysql::explainplan::{Executor, ExplainPlan, ExplainPlanContext, TBProtocol};
+use crate::handler::mysql::keygen::{fill_generated_keys, KeyBinding};
+use crate::discovery::{breaker, health};
+use crate::discovery::SegmentGroup;
+use crate::handler::mysql::rdbc::{encode_text_rows, error_payload, read_max_lag_ms, statement_timeout_ms};
 use crate::handler::parser;
-use crate::protocol::DatabasePacket;
-use crate::protocol::mysql::packet::{MySQLPacketHeader, MySQLPacketPayload};
+use crate::handler::parser::sql::mysql::{route_hints, strip_route_hints};
+use crate::protocol::{DatabasePacket, PacketPayload};
+use crate::protocol::mysql::packet::{MySQLErrPacket, MySQLOKPacket, MySQLPacketHeader, MySQLPacketPayload};
 use crate::protocol::mysql::packet::text::MySQLComQueryPacket;
-use crate::session::mysql::SessionContext;
+use crate::session::mysql::{ReadConsistency, SessionContext};
 
 pub struct ComQueryHandler {}
 
@@ -30,19 +36,138 @@ impl CommandHandler<MySQLPacketPayload, SessionContext> for ComQueryHandler {
         let cow_sql = String::from_utf8_lossy(command_sql.as_slice());
         let sql = cow_sql.to_string();
         println!("SQL = {}", sql);
+        let hints = route_hints(sql.as_str());
         let mut statement = parser::sql::mysql::parser(sql);
-        let statement = statement.pop().unwrap();
+        let mut statement = statement.pop().unwrap();
 
-        let x_query_context = ExplainPlanContext::new(cow_sql.as_ref(),
-                                                      &statement, TBProtocol::Text);
-        let plan = ExplainPlan::new(&x_query_context);
+        if let Some(payloads) = SetVariableHandler::handle_proxy_variable(&statement, session_ctx) {
+            return Some(payloads);
+        }
+        if let Some(payloads) = AdminHandler::handle_admin_statement(&statement) {
+            return Some(payloads);
+        }
 
-        plan.execute()
+        let generated_key = match fill_generated_keys(&mut statement, session_ctx.get_database().as_str(), KeyBinding::Literal) {
+            Ok(generated) => generated.and_then(|generated| generated.get_first_id()),
+            Err(e) => return Some(vec![error_payload(e)]),
+        };
+        // The SQL sent on is the statement with its generated keys, or without the proxy's hints.
+        let sql = match generated_key {
+            Some(_) => statement.to_string(),
+            None => strip_route_hints(cow_sql.as_ref()),
+        };
+        let mut x_query_context = ExplainPlanContext::new(sql.as_str(),
+                                                          &statement, TBProtocol::Text);
+        x_query_context.set_max_lag_ms(read_max_lag_ms(session_ctx, &statement));
+        x_query_context.set_timeout_ms(statement_timeout_ms(&statement, cow_sql.as_ref(), session_ctx));
+        x_query_context.set_route_hints(hints);
+        x_query_context.set_generated_key(generated_key);
+        let mut plan = ExplainPlan::new(&x_query_context);
+        plan.gen(session_ctx);
+
+        plan.execute(session_ctx)
     }
 }
 
 pub struct SetVariableHandler {}
 
+impl SetVariableHandler {
+    /// Answers `SET martlet_*` session variables in the proxy, `None` for anything the backend should see.
+    pub fn handle_proxy_variable(statement: &Statement, session_ctx: &mut SessionContext) -> Option<Vec<Bytes>> {
+        let (variable, value) = match statement {
+            Statement::SetVariable { variable, value, .. } => (variable.value.to_lowercase(), value),
+            _ => return None,
+        };
+        if !variable.starts_with("martlet_") {
+            return None;
+        }
+        let value = match value.first() {
+            Some(SetVariableValue::Ident(ident)) => ident.value.clone(),
+            Some(SetVariableValue::Literal(Value::SingleQuotedString(s))) => s.clone(),
+            Some(SetVariableValue::Literal(v)) => v.to_string(),
+            None => "".to_string(),
+        };
+        let applied = match variable.as_str() {
+            "martlet_read_consistency" => match ReadConsistency::value_of(value.as_str()) {
+                Some(read_consistency) => {
+                    session_ctx.set_read_consistency(read_consistency);
+                    Ok(())
+                }
+                None => Err(format!("Variable '{}' can't be set to the value of '{}'", variable, value)),
+            },
+            _ => Err(format!("Unknown system variable '{}'", variable)),
+        };
+        let payload = match applied {
+            Ok(()) => {
+                let mut ok_packet = MySQLOKPacket::new(1, 0, 0);
+                let mut ok_payload = MySQLPacketPayload::new();
+                DatabasePacket::encode(&mut ok_packet, &mut ok_payload).get_payload()
+            }
+            Err(message) => {
+                let mut err_packet = MySQLErrPacket::new(1, 1231, "42000".to_string(), message);
+                let mut err_payload = MySQLPacketPayload::new();
+                DatabasePacket::encode(&mut err_packet, &mut err_payload).get_payload()
+            }
+        };
+        Some(vec![payload])
+    }
+}
+
+pub struct AdminHandler {}
+
+impl AdminHandler {
+    /// Answers the proxy's `SHOW martlet_*` statements for the admin tooling, `None` for
+    /// anything the backend should see. `SHOW martlet_health` lists the segments as the health
+    /// checker last saw them, `SHOW martlet_breakers` the circuit breaker of each.
+    pub fn handle_admin_statement(statement: &Statement) -> Option<Vec<Bytes>> {
+        let name = match statement {
+            Statement::ShowVariable { variable } if variable.len() == 1 => variable[0].value.to_lowercase(),
+            _ => return None,
+        };
+        match name.as_str() {
+            "martlet_health" => Some(Self::health()),
+            "martlet_breakers" => Some(Self::breakers()),
+            _ => None,
+        }
+    }
+
+    fn health() -> Vec<Bytes> {
+        let promoted = health::promoted_primaries();
+        let rows = health::snapshot().into_iter()
+            .map(|segment| vec![
+                Some(group_name(segment.get_group())),
+                Some(segment.get_url().clone()),
+                Some(format!("{:?}", segment.get_status())),
+                Some(segment.get_failures().to_string()),
+                Some(segment.get_latency_ms().to_string()),
+                segment.get_lag_ms().map(|lag_ms| lag_ms.to_string()),
+                Some(promoted.get(&segment.get_group()).map_or(false, |url| url == segment.get_url()).to_string()),
+                Some(segment.get_last_error().clone()),
+            ])
+            .collect();
+        encode_text_rows(&["segment", "url", "status", "failures", "latency_ms", "lag_ms", "promoted", "last_error"], rows)
+    }
+
+    fn breakers() -> Vec<Bytes> {
+        let rows = breaker::snapshot().into_iter()
+            .map(|breaker| vec![
+                Some(breaker.get_url().clone()),
+                Some(format!("{:?}", breaker.get_state())),
+                Some(breaker.get_recent_failures().to_string()),
+                Some(breaker.get_times_opened().to_string()),
+            ])
+            .collect();
+        encode_text_rows(&["url", "state", "recent_failures", "times_opened"], rows)
+    }
+}
+
+fn group_name(group: SegmentGroup) -> String {
+    match group {
+        SegmentGroup::Meta => String::from("meta"),
+        SegmentGroup::Data(id) => id.to_string(),
+    }
+}
+
 impl CommandHandler<MySQLPacketPayload, SessionContext> for SetVariableHandler {
     fn handle(command_packet_header: Option<MySQLPacketHeader>, command_packet: Option<MySQLPacketPayload>, session_ctx: &mut SessionContext) -> Option<Vec<Bytes>> {
         unimplemented!()
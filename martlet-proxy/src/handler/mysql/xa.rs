@@ -0,0 +1,187 @@
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use mysql::prelude::Queryable;
+use sqlparser::ast::Statement;
+
+use crate::discovery::SegmentGroup;
+use crate::handler::mysql::rdbc::BackendConn;
+use crate::session::mysql::SessionState;
+
+lazy_static! {
+    /// Global transaction ids start with it, unique to this proxy process.
+    static ref XA_PREFIX: String = format!("martlet-{}-{}", process::id(),
+                                           SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis()).unwrap_or(0));
+    static ref XA_SEQUENCE: AtomicU64 = AtomicU64::new(0);
+}
+
+/// Whether the write must run in an XA transaction of its own: it reaches several segment
+/// groups outside a client transaction, whose XA transaction would span them already, rows an
+/// UPDATE moves to the segment group of their new keys included.
+pub fn needs_xa(statement: &Statement, groups: usize, in_transaction: bool) -> bool {
+    let is_write = match statement {
+        Statement::Insert { .. } | Statement::Update { .. } | Statement::Delete { .. } => true,
+        _ => false,
+    };
+    is_write && groups > 1 && !in_transaction
+}
+
+/// Whether the statement ends the client transaction, which MySQL commits or rolls back then:
+/// `COMMIT`, `ROLLBACK`, `START TRANSACTION` and `SET autocommit = 1` with autocommit off.
+pub fn ends_transaction(statement: &Statement, state: &SessionState) -> bool {
+    match statement {
+        Statement::StartTransaction { .. } | Statement::Commit { .. } | Statement::Rollback { savepoint: None, .. } => true,
+        Statement::SetVariable { variable, .. } if variable.value.eq_ignore_ascii_case("autocommit") => {
+            let mut after = state.clone();
+            after.track(statement);
+            !state.get_autocommit() && after.get_autocommit()
+        }
+        _ => false,
+    }
+}
+
+/// One XA transaction over several backend connections, each connection of a segment group
+/// is a branch of it. A client transaction runs as one, from its first statement on a segment
+/// group to its end, so its writes commit on all of them or none.
+#[derive(Debug)]
+pub struct XaTransaction {
+    gtrid: String,
+}
+
+impl XaTransaction {
+    pub fn new() -> Self {
+        let sequence = XA_SEQUENCE.fetch_add(1, Ordering::SeqCst);
+        XaTransaction {
+            gtrid: format!("{}-{}", *XA_PREFIX, sequence),
+        }
+    }
+
+    /// The xid of the segment group's branch, branches of segments sharing a server must differ.
+    fn xid(&self, group: SegmentGroup) -> String {
+        match group {
+            SegmentGroup::Meta => format!("'{}','meta'", self.gtrid),
+            SegmentGroup::Data(id) => format!("'{}','{}'", self.gtrid, id),
+        }
+    }
+
+    pub fn get_gtrid(&self) -> &String {
+        &self.gtrid
+    }
+
+    /// Starts the branch of the connection's segment group.
+    pub fn start_branch(&self, conn: &mut BackendConn) -> mysql::Result<()> {
+        let xid = self.xid(conn.get_group());
+        let started = conn.query_drop(format!("XA START {}", xid));
+        conn.record(&started);
+        started?;
+        conn.set_xa_branch(Some(xid));
+        Ok(())
+    }
+
+    /// Starts a branch on every connection, rolling back those started if one fails.
+    pub fn start(&self, conns: &mut [BackendConn]) -> mysql::Result<()> {
+        for branch in 0..conns.len() {
+            if let Err(e) = self.start_branch(&mut conns[branch]) {
+                self.rollback(&mut conns[..branch]);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Two phase commit of the connections' branches: prepares every branch and commits them
+    /// once all are prepared, rolls all of them back otherwise. A single branch commits in one
+    /// phase. Connections without a branch are left alone.
+    ///
+    /// A branch failing to commit after all prepared stays prepared on its segment, to be
+    /// finished by hand (`XA RECOVER`); the error is returned.
+    pub fn commit(&self, conns: &mut [BackendConn]) -> mysql::Result<()> {
+        let branches: Vec<usize> = (0..conns.len()).filter(|branch| conns[*branch].get_xa_branch().is_some()).collect();
+        if branches.len() == 1 {
+            let conn = &mut conns[branches[0]];
+            let xid = conn.get_xa_branch().cloned().unwrap_or_default();
+            let committed = conn.query_drop(format!("XA END {}", xid))
+                .and_then(|_| conn.query_drop(format!("XA COMMIT {} ONE PHASE", xid)));
+            conn.record(&committed);
+            match committed {
+                Ok(_) => conn.set_xa_branch(None),
+                Err(_) => conn.rollback_xa_branch(),
+            }
+            return committed;
+        }
+        for conn in conns.iter_mut() {
+            let xid = match conn.get_xa_branch() {
+                Some(xid) => xid.clone(),
+                None => continue,
+            };
+            let prepared = conn.query_drop(format!("XA END {}", xid))
+                .and_then(|_| conn.query_drop(format!("XA PREPARE {}", xid)));
+            conn.record(&prepared);
+            if let Err(e) = prepared {
+                self.rollback(conns);
+                return Err(e);
+            }
+        }
+        let mut outcome = Ok(());
+        for conn in conns.iter_mut() {
+            // Prepared branches outlive their connection, none is rolled back from here on.
+            let xid = match conn.get_xa_branch().cloned() {
+                Some(xid) => xid,
+                None => continue,
+            };
+            conn.set_xa_branch(None);
+            let committed = conn.query_drop(format!("XA COMMIT {}", xid));
+            conn.record(&committed);
+            if let Err(e) = committed {
+                println!("error on committing xa branch {} of {}, it is left prepared; error = {:?}", xid, self.gtrid, e);
+                if outcome.is_ok() {
+                    outcome = Err(e);
+                }
+            }
+        }
+        outcome
+    }
+
+    /// Rolls back the connections' branches, whether still active, ended or prepared.
+    pub fn rollback(&self, conns: &mut [BackendConn]) {
+        for conn in conns.iter_mut() {
+            conn.rollback_xa_branch();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::discovery::SegmentGroup;
+    use crate::handler::mysql::xa::{ends_transaction, needs_xa, XaTransaction};
+    use crate::handler::parser::sql::mysql::parser;
+    use crate::session::mysql::SessionState;
+
+    #[test]
+    fn test_needs_xa() {
+        let statement = |sql: &str| parser(sql.to_string()).pop().unwrap();
+        assert!(needs_xa(&statement("DELETE FROM t_dept WHERE id = 1"), 2, false));
+        assert!(!needs_xa(&statement("DELETE FROM t_dept WHERE id = 1"), 1, false));
+        assert!(!needs_xa(&statement("DELETE FROM t_dept WHERE id = 1"), 2, true));
+        assert!(!needs_xa(&statement("SELECT * FROM t_dept"), 2, false));
+        assert_ne!(XaTransaction::new().get_gtrid(), XaTransaction::new().get_gtrid());
+        let xa = XaTransaction::new();
+        assert_ne!(xa.xid(SegmentGroup::Data(100)), xa.xid(SegmentGroup::Data(200)));
+        assert_ne!(xa.xid(SegmentGroup::Meta), xa.xid(SegmentGroup::Data(0)));
+    }
+
+    #[test]
+    fn test_ends_transaction() {
+        let statement = |sql: &str| parser(sql.to_string()).pop().unwrap();
+        let mut state = SessionState::default();
+        assert!(ends_transaction(&statement("COMMIT"), &state));
+        assert!(ends_transaction(&statement("ROLLBACK"), &state));
+        assert!(ends_transaction(&statement("START TRANSACTION"), &state));
+        assert!(!ends_transaction(&statement("SET autocommit = 1"), &state));
+        assert!(!ends_transaction(&statement("UPDATE t_dept SET name = 'a'"), &state));
+        state.track(&statement("SET autocommit = 0"));
+        assert!(!ends_transaction(&statement("SET autocommit = 0"), &state));
+        assert!(ends_transaction(&statement("SET autocommit = 1"), &state));
+    }
+}
@@ -12,10 +12,11 @@
 
 //! SQL Abstract Syntax Tree (AST) types
 
-use sqlparser::ast::{AddDropSync, Assignment, Expr, FileFormat, Function, FunctionArg, HiveDistributionStyle, HiveFormat, HiveIOFormat, HiveRowFormat, Ident, ListAgg, ListAggOnOverflow, ObjectName, ObjectType, SetVariableValue, ShowStatementFilter, SqliteOnConflict, SqlOption, Statement, TransactionAccessMode, TransactionIsolationLevel, TransactionMode, UnaryOperator, WindowFrameBound, WindowFrameUnits, WindowSpec};
+use sqlparser::ast::{AddDropSync, Assignment, Expr, FileFormat, Function, FunctionArg, HiveDistributionStyle, HiveFormat, HiveIOFormat, HiveRowFormat, Ident, ListAgg, ListAggOnOverflow, ObjectName, ObjectType, SetExpr, SetVariableValue, ShowStatementFilter, SqliteOnConflict, SqlOption, Statement, TransactionAccessMode, TransactionIsolationLevel, TransactionMode, UnaryOperator, WindowFrameBound, WindowFrameUnits, WindowSpec};
 use sqlparser::tokenizer::{Token, Whitespace, Word};
 
 // use std::fmt::Write;
+use crate::handler::parser::sql::condition::{InsertRow, ShardingConditions};
 use crate::handler::parser::sql::SQLStatementContext;
 
 mod data_type;
@@ -288,7 +289,9 @@ impl SQLAnalyse for Expr {
                 // write!(f, ")")?;
             }
             Expr::TryCast { .. } => {} // TODO
-            Expr::ParameterMark(_) => {} // TODO
+            Expr::ParameterMark(_) => {
+                ctx.add_parameter();
+            }
         };
         Ok(())
     }
@@ -447,6 +450,7 @@ impl SQLAnalyse for Statement {
             } => {
                 // write!(f, "TRUNCATE TABLE ")?;
                 table_name.analyse(ctx)?;
+                ctx.add_table(table_name.to_string(), String::from(""));
                 if let Some(ref parts) = partitions {
                     if !parts.is_empty() {
                         // write!(f, " PARTITION (")?;
@@ -505,6 +509,8 @@ impl SQLAnalyse for Statement {
                     table_name.analyse(ctx)?;
                     // write!(f, " ")?;
                 }
+                ctx.add_table(table_name.to_string(), String::from(""));
+                ctx.set_insert_table(table_name.to_string());
                 if !columns.is_empty() {
                     // write!(f, "(")?;
                     display_comma_separated(columns).analyse(ctx)?;
@@ -522,7 +528,17 @@ impl SQLAnalyse for Statement {
                     display_comma_separated(after_columns).analyse(ctx)?;
                     // write!(f, ") ")?;
                 }
+                let mut parameter_offset = ctx.get_parameter_count();
                 source.analyse(ctx)?;
+                if let SetExpr::Values(values) = &source.body {
+                    let mut rows = Vec::with_capacity(values.0.len());
+                    for row in &values.0 {
+                        let row = InsertRow::extract(row, parameter_offset);
+                        parameter_offset = row.get_parameters().end;
+                        rows.push(row);
+                    }
+                    ctx.set_insert_rows(columns.iter().map(|column| column.value.clone()).collect(), rows);
+                }
             }
             Statement::Delete {
                 table_name,
@@ -530,14 +546,13 @@ impl SQLAnalyse for Statement {
             } => {
                 // write!(f, "DELETE FROM ")?;
                 table_name.analyse(ctx)?;
-                if table_name.0.len() == 1 {
-                    ctx.add_table(table_name.0.first().unwrap().value.clone(), String::from(""));
-                } else {
-                    ctx.add_table(table_name.0.last().unwrap().value.clone(), String::from(""));
-                }
+                ctx.add_table(table_name.to_string(), String::from(""));
                 if let Some(selection) = selection {
                     // write!(f, " WHERE ")?;
+                    let parameter_offset = ctx.get_parameter_count();
                     selection.analyse(ctx)?;
+                    let conditions = ShardingConditions::extract(selection, parameter_offset, ctx);
+                    ctx.add_conditions(conditions);
                 }
             }
             Statement::Copy {
@@ -576,13 +591,23 @@ impl SQLAnalyse for Statement {
             } => {
                 // write!(f, "UPDATE ")?;
                 table_name.analyse(ctx)?;
+                ctx.add_table(table_name.to_string(), String::from(""));
                 if !assignments.is_empty() {
                     // write!(f, " SET ")?;
+                    let parameter_offset = ctx.get_parameter_count();
                     display_comma_separated(assignments).analyse(ctx)?;
+                    let values: Vec<Expr> = assignments.iter().map(|assignment| assignment.value.clone()).collect();
+                    let row = InsertRow::extract(values.as_slice(), parameter_offset);
+                    ctx.set_assignments(assignments.iter().map(|assignment| assignment.id.value.clone())
+                        .zip(row.get_values().iter().cloned())
+                        .collect());
                 }
                 if let Some(selection) = selection {
                     // write!(f, " WHERE ")?;
+                    let parameter_offset = ctx.get_parameter_count();
                     selection.analyse(ctx)?;
+                    let conditions = ShardingConditions::extract(selection, parameter_offset, ctx);
+                    ctx.add_conditions(conditions);
                 }
                 if let Some(limit) = limit {
                     // write!(f, " LIMIT ")?;
@@ -664,6 +689,7 @@ impl SQLAnalyse for Statement {
                 //     if_not_exists = if *if_not_exists { "IF NOT EXISTS " } else { "" },
                 // )?;
                 name.analyse(ctx)?;
+                ctx.add_table(name.to_string(), String::from(""));
                 if !columns.is_empty() || !constraints.is_empty() {
                     // write!(f, " (")?;
                     display_comma_separated(columns).analyse(ctx)?;
@@ -857,6 +883,7 @@ impl SQLAnalyse for Statement {
                 //     " ON "
                 // )?;
                 table_name.analyse(ctx)?;
+                ctx.add_table(table_name.to_string(), String::from(""));
                 // write!(
                 //     f,
                 //     "("
@@ -870,6 +897,7 @@ impl SQLAnalyse for Statement {
             Statement::AlterTable { name, operation } => {
                 // write!(f, "ALTER TABLE ")?;
                 name.analyse(ctx)?;
+                ctx.add_table(name.to_string(), String::from(""));
                 // write!(f, " ")?;
                 operation.analyse(ctx)?;
             }
@@ -890,6 +918,11 @@ impl SQLAnalyse for Statement {
                 //     if *if_exists { " IF EXISTS" } else { "" }
                 // )?;
                 display_comma_separated(names).analyse(ctx)?;
+                if *object_type == ObjectType::Table {
+                    for name in names {
+                        ctx.add_table(name.to_string(), String::from(""));
+                    }
+                }
                 // write!(
                 //     f,
                 //     "{}{}",
@@ -1066,6 +1099,7 @@ impl SQLAnalyse for FunctionArg {
 /// A function call
 impl SQLAnalyse for Function {
     fn analyse(&self, ctx: &mut SQLStatementContext) -> SAResult {
+        ctx.add_function(self.name.to_string().as_str());
         self.name.analyse(ctx)?;
         // write!(
         //     f,
@@ -1483,6 +1517,8 @@ mod tests {
             }
             SQLStatementContext::Update(_) => {}
             SQLStatementContext::Delete(_) => {}
+            SQLStatementContext::Insert(_) => {}
+            SQLStatementContext::Ddl(_) => {}
             SQLStatementContext::Default => {}
         }
     }
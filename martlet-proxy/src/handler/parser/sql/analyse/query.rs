@@ -13,6 +13,7 @@
 use sqlparser::ast::{Cte, Fetch, Join, JoinConstraint, JoinOperator, Offset, OffsetRows, OrderByExpr, Query, Select, SelectItem, SetExpr, SetOperator, TableAlias, TableFactor, TableWithJoins, Top, Values, With};
 
 use crate::handler::parser::sql::analyse::{display_comma_separated, SQLAnalyse};
+use crate::handler::parser::sql::condition::{ColumnEquality, ShardingConditions};
 use crate::handler::parser::sql::SQLStatementContext;
 
 // use std::fmt::Write;
@@ -112,7 +113,12 @@ impl SQLAnalyse for Select {
         }
         if let Some(ref selection) = self.selection {
             // write!(f, " WHERE ")?;
+            let parameter_offset = ctx.get_parameter_count();
             selection.analyse(ctx)?;
+            let conditions = ShardingConditions::extract(selection, parameter_offset, ctx);
+            ctx.add_conditions(conditions);
+            let equalities = ColumnEquality::extract(selection, ctx);
+            ctx.add_equalities(equalities);
         }
         if !self.group_by.is_empty() {
             // write!(f, " GROUP BY ")?;
@@ -145,6 +151,7 @@ impl SQLAnalyse for With {
 impl SQLAnalyse for Cte {
     fn analyse(&self, ctx: &mut SQLStatementContext) -> SAResult {
         self.alias.analyse(ctx)?;
+        ctx.add_cte(self.alias.name.value.as_str());
         // write!(f, " AS (")?;
         self.query.analyse(ctx)?;
         // write!(f, ")")?;
@@ -281,6 +288,8 @@ impl SQLAnalyse for Join {
                         JoinConstraint::On(expr) => {
                             // write!(f, " ON ")?;
                             expr.analyse(ctx)?;
+                            let equalities = ColumnEquality::extract(expr, ctx);
+                            ctx.add_equalities(equalities);
                             Ok(())
                         }
                         JoinConstraint::Using(attrs) => {
@@ -0,0 +1,511 @@
+use std::collections::HashMap;
+use std::ops::Range;
+
+use sqlparser::ast::{BinaryOperator, Expr, UnaryOperator, Value};
+
+use crate::handler::parser::sql::{SelectStatementContext, SQLStatementContext};
+use crate::handler::parser::sql::analyse::SQLAnalyse;
+use crate::handler::parser::sql::route::logical_table_name;
+
+/// Alternatives of a WHERE clause in disjunctive normal form are capped, beyond this the
+/// clause is treated as restricting nothing.
+const MAX_ALTERNATIVES: usize = 64;
+
+/// What a sharding condition compares the column with.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConditionValue {
+    Literal(Value),
+    /// The `?` at this index of the statement, counted from 0 in textual order.
+    Parameter(usize),
+}
+
+/// One end of a range condition.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RangeBound {
+    value: ConditionValue,
+    inclusive: bool,
+}
+
+impl RangeBound {
+    pub fn new(value: ConditionValue, inclusive: bool) -> Self {
+        RangeBound {
+            value,
+            inclusive,
+        }
+    }
+
+    pub fn get_value(&self) -> &ConditionValue {
+        &self.value
+    }
+
+    pub fn is_inclusive(&self) -> bool {
+        self.inclusive
+    }
+}
+
+/// A predicate on a column the router can prune shards with.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ShardingCondition {
+    Equal(ConditionValue),
+    In(Vec<ConditionValue>),
+    /// Low and high end, `None` is unbounded.
+    Range(Option<RangeBound>, Option<RangeBound>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColumnCondition {
+    /// Logical table of the column.
+    table: String,
+    column: String,
+    condition: ShardingCondition,
+}
+
+impl ColumnCondition {
+    pub fn get_table(&self) -> &String {
+        &self.table
+    }
+
+    pub fn get_column(&self) -> &String {
+        &self.column
+    }
+
+    pub fn get_condition(&self) -> &ShardingCondition {
+        &self.condition
+    }
+
+    fn is_on(&self, table: &str, column: &str) -> bool {
+        self.table.eq_ignore_ascii_case(table) && self.column.eq_ignore_ascii_case(column)
+    }
+}
+
+/// The sharding conditions of one WHERE clause in disjunctive normal form: a row matches when
+/// every condition of one of the alternatives holds. Predicates which cannot be used for
+/// pruning are left out, which only ever widens the set of matching rows.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShardingConditions {
+    alternatives: Vec<Vec<ColumnCondition>>,
+}
+
+impl ShardingConditions {
+    /// Extracts the conditions of a WHERE clause whose first `?` has index `parameter_offset`.
+    /// Columns are resolved against the tables and aliases the context collected so far.
+    pub fn extract(selection: &Expr, parameter_offset: usize, ctx: &SQLStatementContext) -> Self {
+        let tables = match ctx.get_common_ctx() {
+            Some(common_ctx) => common_ctx.get_table_aliases(),
+            None => HashMap::new(),
+        };
+        let mut extractor = ConditionExtractor {
+            tables,
+            next_parameter: parameter_offset,
+        };
+        ShardingConditions {
+            alternatives: extractor.extract(selection),
+        }
+    }
+
+    pub fn get_alternatives(&self) -> &Vec<Vec<ColumnCondition>> {
+        &self.alternatives
+    }
+
+    /// The conditions on the column in each alternative, `None` when one of the
+    /// alternatives does not restrict the column and every shard may match.
+    pub fn get_column_conditions(&self, table: &str, column: &str) -> Option<Vec<Vec<&ShardingCondition>>> {
+        let mut column_conditions = Vec::new();
+        for alternative in &self.alternatives {
+            let conditions: Vec<&ShardingCondition> = alternative.iter()
+                .filter(|condition| condition.is_on(table, column))
+                .map(|condition| &condition.condition)
+                .collect();
+            if conditions.is_empty() {
+                return None;
+            }
+            column_conditions.push(conditions);
+        }
+        Some(column_conditions)
+    }
+}
+
+/// `a.x = b.y` between two columns, stated by a join's ON or a WHERE clause for every row.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColumnEquality {
+    left: ColumnRef,
+    right: ColumnRef,
+}
+
+impl ColumnEquality {
+    /// Equalities of the top level conjuncts of the expression, only those hold for every
+    /// row it matches.
+    pub fn extract(expr: &Expr, ctx: &SQLStatementContext) -> Vec<ColumnEquality> {
+        let tables = match ctx.get_common_ctx() {
+            Some(common_ctx) => common_ctx.get_table_aliases(),
+            None => HashMap::new(),
+        };
+        let extractor = ConditionExtractor {
+            tables,
+            next_parameter: 0,
+        };
+        let mut equalities = Vec::new();
+        extractor.equalities(expr, &mut equalities);
+        equalities
+    }
+
+    pub fn get_left(&self) -> &ColumnRef {
+        &self.left
+    }
+
+    pub fn get_right(&self) -> &ColumnRef {
+        &self.right
+    }
+
+    /// Whether it equates `column` of `table` with `other_column` of `other_table`, either way round.
+    pub fn binds(&self, table: &str, column: &str, other_table: &str, other_column: &str) -> bool {
+        (self.left.is(table, column) && self.right.is(other_table, other_column))
+            || (self.right.is(table, column) && self.left.is(other_table, other_column))
+    }
+}
+
+/// One row of an `INSERT ... VALUES`, as far as the router needs it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InsertRow {
+    /// The literal or `?` of each column, `None` for any other expression.
+    values: Vec<Option<ConditionValue>>,
+    /// Indexes of the row's `?`s.
+    parameters: Range<usize>,
+}
+
+impl InsertRow {
+    /// Extracts the row whose first `?` has index `parameter_offset`.
+    pub fn extract(row: &[Expr], parameter_offset: usize) -> Self {
+        let mut extractor = ConditionExtractor {
+            tables: HashMap::new(),
+            next_parameter: parameter_offset,
+        };
+        let values = row.iter().map(|expr| extractor.value(expr)).collect();
+        InsertRow {
+            values,
+            parameters: parameter_offset..extractor.next_parameter,
+        }
+    }
+
+    pub fn get_values(&self) -> &Vec<Option<ConditionValue>> {
+        &self.values
+    }
+
+    pub fn get_parameters(&self) -> &Range<usize> {
+        &self.parameters
+    }
+}
+
+struct ConditionExtractor {
+    /// Logical table names by alias and by their own name.
+    tables: HashMap<String, String>,
+    next_parameter: usize,
+}
+
+impl ConditionExtractor {
+    /// Walks the expression in textual order, so `?`s are numbered like the client sends them.
+    fn extract(&mut self, expr: &Expr) -> Vec<Vec<ColumnCondition>> {
+        match expr {
+            Expr::Nested(expr) => self.extract(expr),
+            Expr::BinaryOp { left, op: BinaryOperator::And, right } => {
+                let left = self.extract(left);
+                let right = self.extract(right);
+                if left.len() * right.len() > MAX_ALTERNATIVES {
+                    return vec![vec![]];
+                }
+                let mut alternatives = Vec::new();
+                for l in &left {
+                    for r in &right {
+                        let mut alternative = l.clone();
+                        alternative.extend(r.iter().cloned());
+                        alternatives.push(alternative);
+                    }
+                }
+                alternatives
+            }
+            Expr::BinaryOp { left, op: BinaryOperator::Or, right } => {
+                let mut alternatives = self.extract(left);
+                alternatives.extend(self.extract(right));
+                if alternatives.len() > MAX_ALTERNATIVES {
+                    return vec![vec![]];
+                }
+                alternatives
+            }
+            Expr::BinaryOp { left, op, right } => {
+                let left_column = self.column(left);
+                let left_value = match left_column {
+                    Some(_) => None,
+                    None => self.value(left),
+                };
+                let right_column = self.column(right);
+                let right_value = match right_column {
+                    Some(_) => None,
+                    None => self.value(right),
+                };
+                let (column, value, op) = match (left_column, left_value, right_column, right_value) {
+                    (Some(column), _, None, Some(value)) => (column, value, op.clone()),
+                    (None, Some(value), Some(column), _) => (column, value, flip(op)),
+                    _ => return vec![vec![]],
+                };
+                let condition = match op {
+                    BinaryOperator::Eq => ShardingCondition::Equal(value),
+                    BinaryOperator::Gt => ShardingCondition::Range(Some(RangeBound::new(value, false)), None),
+                    BinaryOperator::GtEq => ShardingCondition::Range(Some(RangeBound::new(value, true)), None),
+                    BinaryOperator::Lt => ShardingCondition::Range(None, Some(RangeBound::new(value, false))),
+                    BinaryOperator::LtEq => ShardingCondition::Range(None, Some(RangeBound::new(value, true))),
+                    _ => return vec![vec![]],
+                };
+                vec![vec![column.with(condition)]]
+            }
+            Expr::InList { expr, list, negated } => {
+                let column = self.column(expr);
+                if column.is_none() {
+                    self.skip(expr);
+                }
+                let values: Vec<Option<ConditionValue>> = list.iter().map(|item| self.value(item)).collect();
+                match column {
+                    Some(column) if !*negated && values.iter().all(|value| value.is_some()) => {
+                        let values = values.into_iter().map(|value| value.unwrap()).collect();
+                        vec![vec![column.with(ShardingCondition::In(values))]]
+                    }
+                    _ => vec![vec![]],
+                }
+            }
+            Expr::Between { expr, negated, low, high } => {
+                let column = self.column(expr);
+                if column.is_none() {
+                    self.skip(expr);
+                }
+                let low = self.value(low);
+                let high = self.value(high);
+                match (column, low, high) {
+                    (Some(column), Some(low), Some(high)) if !*negated => {
+                        let condition = ShardingCondition::Range(Some(RangeBound::new(low, true)), Some(RangeBound::new(high, true)));
+                        vec![vec![column.with(condition)]]
+                    }
+                    _ => vec![vec![]],
+                }
+            }
+            _ => {
+                self.skip(expr);
+                vec![vec![]]
+            }
+        }
+    }
+
+    fn equalities(&self, expr: &Expr, equalities: &mut Vec<ColumnEquality>) {
+        match expr {
+            Expr::Nested(expr) => self.equalities(expr, equalities),
+            Expr::BinaryOp { left, op: BinaryOperator::And, right } => {
+                self.equalities(left, equalities);
+                self.equalities(right, equalities);
+            }
+            Expr::BinaryOp { left, op: BinaryOperator::Eq, right } => {
+                if let (Some(left), Some(right)) = (self.column(left), self.column(right)) {
+                    equalities.push(ColumnEquality {
+                        left,
+                        right,
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Numbers the `?`s of an expression not used as a condition.
+    fn skip(&mut self, expr: &Expr) {
+        let mut counter = SQLStatementContext::Select(SelectStatementContext::new());
+        if expr.analyse(&mut counter).is_ok() {
+            self.next_parameter = self.next_parameter + counter.get_parameter_count();
+        }
+    }
+
+    /// The column the expression names, its `?`s are not counted. An unqualified column is
+    /// only known with a single table in scope; with several it may be any one's, and pruning
+    /// a table on another's column would lose rows.
+    fn column(&self, expr: &Expr) -> Option<ColumnRef> {
+        match expr {
+            Expr::Nested(expr) => self.column(expr),
+            Expr::Identifier(ident) if self.distinct_tables() == 1 => {
+                Some(ColumnRef {
+                    table: self.tables.values().next().cloned().unwrap_or_default(),
+                    column: ident.value.clone(),
+                })
+            }
+            Expr::CompoundIdentifier(idents) if idents.len() >= 2 => {
+                let qualifier = &idents[idents.len() - 2].value;
+                let table = self.tables.iter()
+                    .find(|(name, _)| name.eq_ignore_ascii_case(qualifier))
+                    .map(|(_, table)| table.clone())
+                    .unwrap_or_else(|| qualifier.clone());
+                Some(ColumnRef {
+                    table,
+                    column: idents[idents.len() - 1].value.clone(),
+                })
+            }
+            _ => None,
+        }
+    }
+
+    fn distinct_tables(&self) -> usize {
+        let mut tables: Vec<&String> = self.tables.values().collect();
+        tables.sort();
+        tables.dedup();
+        tables.len()
+    }
+
+    /// The literal or `?` the expression is, counting the `?`s of anything else.
+    fn value(&mut self, expr: &Expr) -> Option<ConditionValue> {
+        match expr {
+            Expr::Nested(expr) => self.value(expr),
+            Expr::Value(value) => Some(ConditionValue::Literal(value.clone())),
+            Expr::ParameterMark(_) => {
+                let index = self.next_parameter;
+                self.next_parameter = self.next_parameter + 1;
+                Some(ConditionValue::Parameter(index))
+            }
+            Expr::UnaryOp { op: UnaryOperator::Minus, expr } => match expr.as_ref() {
+                Expr::Value(Value::Number(number, long)) => Some(ConditionValue::Literal(Value::Number(format!("-{}", number), *long))),
+                _ => {
+                    self.skip(expr);
+                    None
+                }
+            },
+            _ => {
+                self.skip(expr);
+                None
+            }
+        }
+    }
+}
+
+/// A column of the statement, `table` is its logical table.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColumnRef {
+    table: String,
+    column: String,
+}
+
+impl ColumnRef {
+    pub fn get_table(&self) -> &String {
+        &self.table
+    }
+
+    pub fn get_column(&self) -> &String {
+        &self.column
+    }
+
+    fn is(&self, table: &str, column: &str) -> bool {
+        self.table.eq_ignore_ascii_case(table) && self.column.eq_ignore_ascii_case(column)
+    }
+
+    fn with(self, condition: ShardingCondition) -> ColumnCondition {
+        ColumnCondition {
+            table: self.table,
+            column: self.column,
+            condition,
+        }
+    }
+}
+
+/// The operator with its operands swapped, `5 < col` is `col > 5`.
+fn flip(op: &BinaryOperator) -> BinaryOperator {
+    match op {
+        BinaryOperator::Gt => BinaryOperator::Lt,
+        BinaryOperator::Lt => BinaryOperator::Gt,
+        BinaryOperator::GtEq => BinaryOperator::LtEq,
+        BinaryOperator::LtEq => BinaryOperator::GtEq,
+        op => op.clone(),
+    }
+}
+
+/// Logical table names of the context's tables, by alias and by their own name.
+pub fn table_aliases(tables: &HashMap<String, String>) -> HashMap<String, String> {
+    let mut aliases = HashMap::new();
+    for (table, alias) in tables {
+        let logical_table = logical_table_name(table);
+        if !alias.is_empty() {
+            aliases.insert(alias.clone(), logical_table.clone());
+        }
+        aliases.insert(logical_table.clone(), logical_table);
+    }
+    aliases
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlparser::ast::Value;
+
+    use crate::handler::parser::sql::condition::{ConditionValue, RangeBound, ShardingCondition};
+    use crate::handler::parser::sql::mysql::parser;
+    use crate::handler::parser::sql::analyse::SQLAnalyse;
+    use crate::handler::parser::sql::SQLStatementContext;
+
+    fn analyse(sql: &str) -> SQLStatementContext {
+        let statement = parser(sql.to_string()).pop().unwrap();
+        let mut ctx = SQLStatementContext::new(&statement);
+        statement.analyse(&mut ctx).unwrap();
+        ctx
+    }
+
+    fn number(n: &str) -> ConditionValue {
+        ConditionValue::Literal(Value::Number(n.to_string(), false))
+    }
+
+    #[test]
+    fn test_sharding_conditions() {
+        let ctx = analyse("SELECT * FROM t_order o JOIN t_user u ON o.user_id = u.id \
+                           WHERE o.user_id = 10 AND (u.age > ? OR o.user_id IN (11, ?)) AND o.status = ?");
+        let conditions = &ctx.get_conditions()[0];
+        assert_eq!(conditions.get_alternatives().len(), 2);
+        assert_eq!(conditions.get_column_conditions("t_order", "user_id").unwrap(), vec![
+            vec![&ShardingCondition::Equal(number("10"))],
+            vec![&ShardingCondition::Equal(number("10")), &ShardingCondition::In(vec![number("11"), ConditionValue::Parameter(1)])],
+        ]);
+        assert_eq!(conditions.get_column_conditions("t_user", "age"), None);
+        assert_eq!(conditions.get_column_conditions("t_order", "status").unwrap()[0],
+                   vec![&ShardingCondition::Equal(ConditionValue::Parameter(2))]);
+
+        let ctx = analyse("DELETE FROM t_order WHERE user_id BETWEEN 1 AND 5 OR 100 <= user_id");
+        assert_eq!(ctx.get_conditions()[0].get_column_conditions("t_order", "user_id").unwrap(), vec![
+            vec![&ShardingCondition::Range(Some(RangeBound::new(number("1"), true)), Some(RangeBound::new(number("5"), true)))],
+            vec![&ShardingCondition::Range(Some(RangeBound::new(number("100"), true)), None)],
+        ]);
+
+        let ctx = analyse("SELECT * FROM t_order o JOIN t_user u ON o.user_id = u.id WHERE user_id = 5 AND o.status = ?");
+        let conditions = &ctx.get_conditions()[0];
+        assert_eq!(conditions.get_column_conditions("t_order", "user_id"), None);
+        assert_eq!(conditions.get_column_conditions("t_user", "user_id"), None);
+        assert_eq!(conditions.get_column_conditions("t_order", "status").unwrap()[0],
+                   vec![&ShardingCondition::Equal(ConditionValue::Parameter(0))]);
+
+        let ctx = analyse("UPDATE t_order SET status = ? WHERE user_id = ? OR name LIKE 'a%'");
+        let conditions = &ctx.get_conditions()[0];
+        assert_eq!(conditions.get_column_conditions("t_order", "user_id"), None);
+        assert_eq!(conditions.get_alternatives()[0][0].get_condition(), &ShardingCondition::Equal(ConditionValue::Parameter(1)));
+    }
+
+    #[test]
+    fn test_insert_rows() {
+        let ctx = analyse("INSERT INTO martlet.t_order (user_id, status, note) VALUES (?, 1, 'a'), (-2, ?, CONCAT(?, 'b'))");
+        let rows = ctx.get_insert_rows();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].get_values(), &vec![Some(ConditionValue::Parameter(0)), Some(number("1")),
+                                               Some(ConditionValue::Literal(Value::SingleQuotedString(String::from("a"))))]);
+        assert_eq!(rows[0].get_parameters(), &(0..1));
+        assert_eq!(rows[1].get_values(), &vec![Some(number("-2")), Some(ConditionValue::Parameter(1)), None]);
+        assert_eq!(rows[1].get_parameters(), &(1..3));
+        assert_eq!(ctx.get_insert_columns(), &vec![String::from("user_id"), String::from("status"), String::from("note")]);
+    }
+
+    #[test]
+    fn test_column_equalities() {
+        let ctx = analyse("SELECT * FROM t_order o JOIN t_order_item i ON o.user_id = i.user_id AND i.status = 1 \
+                           WHERE (o.order_id = i.order_id) AND (o.id = i.id OR o.x = 1)");
+        let equalities = ctx.get_equalities();
+        assert_eq!(equalities.len(), 2);
+        assert!(equalities[0].binds("t_order_item", "user_id", "t_order", "USER_ID"));
+        assert!(equalities[1].binds("t_order", "order_id", "t_order_item", "order_id"));
+        assert!(!equalities[1].binds("t_order", "user_id", "t_order_item", "user_id"));
+    }
+}
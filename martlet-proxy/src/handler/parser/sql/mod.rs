@@ -1,48 +1,255 @@
 use std::collections::HashMap;
 
+use sqlparser::ast::Statement;
+
+use crate::handler::parser::sql::analyse::SQLAnalyse;
+use crate::handler::parser::sql::condition::{ColumnEquality, ConditionValue, InsertRow, ShardingConditions, table_aliases};
+
 pub mod mysql;
 pub mod postgresql;
 
 pub mod rewrite;
 pub mod analyse;
+pub mod condition;
 pub mod route;
 
+/// Upper-cased names of the functions the statement calls, none if it cannot be analysed.
+pub fn called_functions(statement: &Statement) -> Vec<String> {
+    let mut stmt_ctx = SQLStatementContext::new(statement);
+    match statement.analyse(&mut stmt_ctx) {
+        Ok(_) => stmt_ctx.get_functions().to_vec(),
+        Err(_) => vec![],
+    }
+}
+
 pub enum SQLStatementContext {
     Select(SelectStatementContext),
     Update(UpdateStatementContext),
     Delete(DeleteStatementContext),
+    Insert(InsertStatementContext),
+    Ddl(DdlStatementContext),
     Default,
 }
 
 impl SQLStatementContext {
+    /// Context matching the kind of the statement, to be filled by `SQLAnalyse`.
+    pub fn new(statement: &Statement) -> Self {
+        match statement {
+            Statement::Query(_) => SQLStatementContext::Select(SelectStatementContext::new()),
+            Statement::Update { .. } => SQLStatementContext::Update(UpdateStatementContext::new()),
+            Statement::Delete { .. } => SQLStatementContext::Delete(DeleteStatementContext::new()),
+            Statement::Insert { .. } => SQLStatementContext::Insert(InsertStatementContext::new()),
+            Statement::CreateTable { .. } | Statement::CreateIndex { .. } | Statement::AlterTable { .. }
+            | Statement::Drop { .. } | Statement::Truncate { .. } => SQLStatementContext::Ddl(DdlStatementContext::new()),
+            _ => SQLStatementContext::Default,
+        }
+    }
+
     pub fn add_table(&mut self, table: String, alias: String) {
         match self {
             SQLStatementContext::Select(s) => {
                 s.common_ctx.add_table(table, alias);
             }
-            SQLStatementContext::Update(_) => {}
+            SQLStatementContext::Update(s) => {
+                s.common_ctx.add_table(table, alias);
+            }
             SQLStatementContext::Delete(s) => {
                 s.common_ctx.add_table(table, alias);
             }
+            SQLStatementContext::Insert(s) => {
+                s.common_ctx.add_table(table, alias);
+            }
+            SQLStatementContext::Ddl(s) => {
+                s.common_ctx.add_table(table, alias);
+            }
             SQLStatementContext::Default => {}
         }
     }
+
+    pub fn get_tables(&self) -> Vec<String> {
+        match self.get_common_ctx() {
+            Some(common_ctx) => common_ctx.get_tables(),
+            None => vec![],
+        }
+    }
+
+    pub fn get_common_ctx(&self) -> Option<&CommonStatementContext> {
+        match self {
+            SQLStatementContext::Select(s) => Some(&s.common_ctx),
+            SQLStatementContext::Update(s) => Some(&s.common_ctx),
+            SQLStatementContext::Delete(s) => Some(&s.common_ctx),
+            SQLStatementContext::Insert(s) => Some(&s.common_ctx),
+            SQLStatementContext::Ddl(s) => Some(&s.common_ctx),
+            SQLStatementContext::Default => None,
+        }
+    }
+
+    fn get_common_ctx_mut(&mut self) -> Option<&mut CommonStatementContext> {
+        match self {
+            SQLStatementContext::Select(s) => Some(&mut s.common_ctx),
+            SQLStatementContext::Update(s) => Some(&mut s.common_ctx),
+            SQLStatementContext::Delete(s) => Some(&mut s.common_ctx),
+            SQLStatementContext::Insert(s) => Some(&mut s.common_ctx),
+            SQLStatementContext::Ddl(s) => Some(&mut s.common_ctx),
+            SQLStatementContext::Default => None,
+        }
+    }
+
+    /// Declares a common table expression, references to it are not taken for tables.
+    pub fn add_cte(&mut self, name: &str) {
+        if let Some(common_ctx) = self.get_common_ctx_mut() {
+            common_ctx.ctes.push(name.to_lowercase());
+        }
+    }
+
+    /// Counts a `?` of the statement.
+    pub fn add_parameter(&mut self) {
+        if let Some(common_ctx) = self.get_common_ctx_mut() {
+            common_ctx.parameter_count = common_ctx.parameter_count + 1;
+        }
+    }
+
+    /// Records a call of the function, by its upper-cased name without schema.
+    pub fn add_function(&mut self, name: &str) {
+        if let Some(common_ctx) = self.get_common_ctx_mut() {
+            let name = name.rsplit('.').next().unwrap_or(name).trim_matches('`');
+            common_ctx.functions.push(name.to_uppercase());
+        }
+    }
+
+    /// Upper-cased names of the functions the statement calls, once per call.
+    pub fn get_functions(&self) -> &[String] {
+        match self.get_common_ctx() {
+            Some(common_ctx) => common_ctx.functions.as_slice(),
+            None => &[],
+        }
+    }
+
+    /// `?`s analysed so far.
+    pub fn get_parameter_count(&self) -> usize {
+        self.get_common_ctx().map(|common_ctx| common_ctx.parameter_count).unwrap_or(0)
+    }
+
+    pub fn add_conditions(&mut self, conditions: ShardingConditions) {
+        if let Some(common_ctx) = self.get_common_ctx_mut() {
+            common_ctx.conditions.push(conditions);
+        }
+    }
+
+    pub fn add_equalities(&mut self, equalities: Vec<ColumnEquality>) {
+        if let Some(common_ctx) = self.get_common_ctx_mut() {
+            common_ctx.equalities.extend(equalities);
+        }
+    }
+
+    /// Equalities between columns the joins and WHERE clauses of the statement state.
+    pub fn get_equalities(&self) -> &[ColumnEquality] {
+        match self.get_common_ctx() {
+            Some(common_ctx) => common_ctx.equalities.as_slice(),
+            None => &[],
+        }
+    }
+
+    /// Records the table an INSERT writes.
+    pub fn set_insert_table(&mut self, table: String) {
+        if let SQLStatementContext::Insert(s) = self {
+            s.table = table;
+        }
+    }
+
+    /// Table an INSERT writes, `None` for other statements.
+    pub fn get_insert_table(&self) -> Option<&str> {
+        match self {
+            SQLStatementContext::Insert(s) => Some(s.table.as_str()),
+            _ => None,
+        }
+    }
+
+    /// Records the columns and `VALUES` rows of an INSERT.
+    pub fn set_insert_rows(&mut self, columns: Vec<String>, rows: Vec<InsertRow>) {
+        if let SQLStatementContext::Insert(s) = self {
+            s.columns = columns;
+            s.rows = rows;
+        }
+    }
+
+    /// Columns listed by an INSERT, empty when it lists none.
+    pub fn get_insert_columns(&self) -> &[String] {
+        match self {
+            SQLStatementContext::Insert(s) => s.columns.as_slice(),
+            _ => &[],
+        }
+    }
+
+    /// Rows of an `INSERT ... VALUES`, empty for other statements.
+    pub fn get_insert_rows(&self) -> &[InsertRow] {
+        match self {
+            SQLStatementContext::Insert(s) => s.rows.as_slice(),
+            _ => &[],
+        }
+    }
+
+    /// Records the SET clause of an UPDATE.
+    pub fn set_assignments(&mut self, assignments: Vec<(String, Option<ConditionValue>)>) {
+        if let SQLStatementContext::Update(s) = self {
+            s.assignments = assignments;
+        }
+    }
+
+    /// Columns an UPDATE assigns and their literal or `?` values, empty for other statements.
+    pub fn get_assignments(&self) -> &[(String, Option<ConditionValue>)] {
+        match self {
+            SQLStatementContext::Update(s) => s.assignments.as_slice(),
+            _ => &[],
+        }
+    }
+
+    /// Sharding conditions of every WHERE clause of the statement, subqueries included.
+    pub fn get_conditions(&self) -> &[ShardingConditions] {
+        match self.get_common_ctx() {
+            Some(common_ctx) => common_ctx.conditions.as_slice(),
+            None => &[],
+        }
+    }
 }
 
 pub struct CommonStatementContext {
     tables: HashMap<String, String>,
+    /// Lower-cased names of the statement's common table expressions, which are no tables.
+    ctes: Vec<String>,
+    parameter_count: usize,
+    conditions: Vec<ShardingConditions>,
+    equalities: Vec<ColumnEquality>,
+    functions: Vec<String>,
 }
 
 impl CommonStatementContext {
     pub fn new() -> Self {
         CommonStatementContext {
             tables: Default::default(),
+            ctes: vec![],
+            parameter_count: 0,
+            conditions: vec![],
+            equalities: vec![],
+            functions: vec![],
         }
     }
 
     pub fn add_table(&mut self, table: String, alias: String) {
+        if !table.contains('.') && self.ctes.contains(&table.to_lowercase()) {
+            return;
+        }
         self.tables.insert(table, alias);
     }
+
+    pub fn get_tables(&self) -> Vec<String> {
+        self.tables.keys().cloned().collect()
+    }
+
+    /// Logical table names by alias and by their own name.
+    pub fn get_table_aliases(&self) -> HashMap<String, String> {
+        table_aliases(&self.tables)
+    }
 }
 
 pub struct SelectStatementContext {
@@ -63,12 +270,15 @@ impl SelectStatementContext {
 
 pub struct UpdateStatementContext {
     common_ctx: CommonStatementContext,
+    /// Columns the SET clause assigns, with the literal or `?` each gets.
+    assignments: Vec<(String, Option<ConditionValue>)>,
 }
 
 impl UpdateStatementContext {
     pub fn new() -> Self {
         UpdateStatementContext {
-            common_ctx: CommonStatementContext::new()
+            common_ctx: CommonStatementContext::new(),
+            assignments: vec![],
         }
     }
 
@@ -93,4 +303,39 @@ impl DeleteStatementContext {
     }
 }
 
+pub struct InsertStatementContext {
+    common_ctx: CommonStatementContext,
+    table: String,
+    columns: Vec<String>,
+    rows: Vec<InsertRow>,
+}
+
+impl InsertStatementContext {
+    pub fn new() -> Self {
+        InsertStatementContext {
+            common_ctx: CommonStatementContext::new(),
+            table: String::new(),
+            columns: vec![],
+            rows: vec![],
+        }
+    }
+
+    pub fn add_table(&mut self, table: String, alias: String) {
+        self.common_ctx.tables.insert(table, alias);
+    }
+}
+
+/// Context of a statement creating, altering or dropping tables, which names only them.
+pub struct DdlStatementContext {
+    common_ctx: CommonStatementContext,
+}
+
+impl DdlStatementContext {
+    pub fn new() -> Self {
+        DdlStatementContext {
+            common_ctx: CommonStatementContext::new()
+        }
+    }
+}
+
 pub struct SQLRewriteContext {}
\ No newline at end of file
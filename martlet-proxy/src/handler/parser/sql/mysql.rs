@@ -2,6 +2,8 @@ use sqlparser::ast::{Ident, Statement};
 use sqlparser::dialect::Dialect;
 use sqlparser::parser::Parser;
 
+use crate::handler::parser::sql::route::value::ShardValue;
+
 #[derive(Debug)]
 pub struct MySQLDialect {}
 
@@ -43,4 +45,210 @@ pub fn parser(sql: String) -> Vec<Statement> {
     };
 
     ast
-}
\ No newline at end of file
+}
+/// Byte ranges of the terminated `/*+ ... */` comments of the SQL, `/*+` and `*/` included.
+/// Quoted strings and identifiers, plain comments and line comments are skipped.
+fn hint_comments(sql: &str) -> Vec<(usize, usize)> {
+    let bytes = sql.as_bytes();
+    let mut comments = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            quote @ b'\'' | quote @ b'"' | quote @ b'`' => {
+                i = i + 1;
+                while i < bytes.len() {
+                    if bytes[i] == b'\\' && quote != b'`' {
+                        i = i + 2;
+                        continue;
+                    }
+                    if bytes[i] == quote {
+                        // A doubled quote stands for the quote itself.
+                        if bytes.get(i + 1) != Some(&quote) {
+                            break;
+                        }
+                        i = i + 1;
+                    }
+                    i = i + 1;
+                }
+                i = i + 1;
+            }
+            b'/' if bytes.get(i + 1) == Some(&b'*') => {
+                let end = match sql[i + 2..].find("*/") {
+                    Some(end) => i + 2 + end + 2,
+                    None => break,
+                };
+                if bytes.get(i + 2) == Some(&b'+') {
+                    comments.push((i, end));
+                }
+                i = end;
+            }
+            b'#' => i = sql[i..].find('\n').map_or(bytes.len(), |end| i + end),
+            b'-' if bytes.get(i + 1) == Some(&b'-') && bytes.get(i + 2).map_or(true, |c| c.is_ascii_whitespace()) => {
+                i = sql[i..].find('\n').map_or(bytes.len(), |end| i + end);
+            }
+            _ => i = i + 1,
+        }
+    }
+    comments
+}
+
+/// Optimizer hints of the `/*+ ... */` comments in the SQL, e.g. `MAX_EXECUTION_TIME(1000)`
+/// becomes `("MAX_EXECUTION_TIME", ["1000"])`. Names are upper-cased, arguments trimmed.
+pub fn optimizer_hints(sql: &str) -> Vec<(String, Vec<String>)> {
+    let mut hints = Vec::new();
+    for (start, end) in hint_comments(sql) {
+        let mut body = &sql[start + 3..end - 2];
+        while let Some(open) = body.find('(') {
+            let close = match body[open..].find(')') {
+                Some(close) => open + close,
+                None => break,
+            };
+            // Hints without arguments before the name are skipped.
+            let name = body[..open].split_whitespace().last().unwrap_or("").to_uppercase();
+            let args = body[open + 1..close].split(',')
+                .map(|arg| arg.trim().to_string())
+                .filter(|arg| !arg.is_empty())
+                .collect();
+            hints.push((name, args));
+            body = &body[close + 1..];
+        }
+    }
+    hints
+}
+
+/// Milliseconds of a `/*+ MAX_EXECUTION_TIME(n) */` hint.
+pub fn max_execution_time_hint(sql: &str) -> Option<u64> {
+    optimizer_hints(sql).into_iter()
+        .find(|(name, _)| name == "MAX_EXECUTION_TIME")
+        .and_then(|(_, args)| args.first().and_then(|arg| arg.parse::<u64>().ok()))
+}
+
+/// Routing overrides of the `/*+ MARTLET_* */` hints in the SQL.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct RouteHints {
+    /// `MARTLET_SEGMENT(id)`: the statement runs on that data segment only.
+    segment: Option<u32>,
+    /// `MARTLET_SHARD_VALUE(table, value)`: the table's rows are those of its `dis_keys` value.
+    shard_values: Vec<(String, ShardValue)>,
+    /// `MARTLET_PRIMARY`: reads go to the primary rather than a mirror.
+    primary: bool,
+}
+
+impl RouteHints {
+    pub fn get_segment(&self) -> Option<u32> {
+        self.segment
+    }
+
+    pub fn get_shard_values(&self) -> &Vec<(String, ShardValue)> {
+        &self.shard_values
+    }
+
+    /// Hinted value of the table's sharding key, if any.
+    pub fn get_shard_value(&self, table: &str) -> Option<&ShardValue> {
+        self.shard_values.iter()
+            .find(|(hinted, _)| hinted.eq_ignore_ascii_case(table))
+            .map(|(_, value)| value)
+    }
+
+    pub fn is_primary(&self) -> bool {
+        self.primary
+    }
+}
+
+/// The `MARTLET_*` hints of the SQL, looked for before it is parsed. `MARTLET_PRIMARY` takes no
+/// arguments and is also recognised without the parentheses.
+pub fn route_hints(sql: &str) -> RouteHints {
+    let mut hints = RouteHints::default();
+    for (name, args) in optimizer_hints(sql) {
+        match name.as_str() {
+            "MARTLET_SEGMENT" => hints.segment = args.first().and_then(|arg| arg.parse::<u32>().ok()),
+            "MARTLET_SHARD_VALUE" if args.len() == 2 => {
+                let table = args[0].trim_matches(|c| c == '`' || c == '"').to_string();
+                let value = args[1].trim_matches(|c| c == '\'' || c == '"');
+                hints.shard_values.push((table, ShardValue::from_text(value.as_bytes())));
+            }
+            "MARTLET_PRIMARY" => hints.primary = true,
+            _ => {}
+        }
+    }
+    for (start, end) in hint_comments(sql) {
+        if sql[start + 3..end - 2].split_whitespace().any(|word| word.eq_ignore_ascii_case("MARTLET_PRIMARY")) {
+            hints.primary = true;
+        }
+    }
+    hints
+}
+
+/// The SQL without its `MARTLET_*` hints, which are the proxy's and not the backends'. Hint
+/// comments left with no other hint are dropped.
+pub fn strip_route_hints(sql: &str) -> String {
+    let mut stripped = String::with_capacity(sql.len());
+    let mut last = 0;
+    for (start, end) in hint_comments(sql) {
+        stripped.push_str(&sql[last..start]);
+        let mut kept = String::new();
+        let mut rest = sql[start + 3..end - 2].trim_start();
+        while !rest.is_empty() {
+            let name_end = rest.find(|c: char| c.is_whitespace() || c == '(').unwrap_or(rest.len());
+            let after_name = rest[name_end..].trim_start();
+            let hint_end = if after_name.starts_with('(') {
+                let open = rest.len() - after_name.len();
+                rest[open..].find(')').map_or(rest.len(), |close| open + close + 1)
+            } else {
+                name_end
+            };
+            if !rest[..name_end].to_uppercase().starts_with("MARTLET_") {
+                kept.push(' ');
+                kept.push_str(&rest[..hint_end]);
+            }
+            rest = rest[hint_end..].trim_start();
+        }
+        if !kept.is_empty() {
+            stripped.push_str(format!("/*+{} */", kept).as_str());
+        }
+        last = end;
+    }
+    stripped.push_str(&sql[last..]);
+    stripped
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::handler::parser::sql::mysql::{max_execution_time_hint, optimizer_hints, route_hints, strip_route_hints};
+    use crate::handler::parser::sql::route::value::ShardValue;
+
+    #[test]
+    fn test_optimizer_hints() {
+        let sql = "SELECT /*+ MAX_EXECUTION_TIME(1000) BKA(t1) */ * FROM t1 /* plain */ WHERE id = 1";
+        assert_eq!(vec![("MAX_EXECUTION_TIME".to_string(), vec!["1000".to_string()]),
+                        ("BKA".to_string(), vec!["t1".to_string()])], optimizer_hints(sql));
+        assert_eq!(Some(1000), max_execution_time_hint(sql));
+        assert_eq!(None, max_execution_time_hint("SELECT * FROM t1"));
+    }
+
+    #[test]
+    fn test_route_hints() {
+        let hints = route_hints("SELECT /*+ MARTLET_SEGMENT(200) MARTLET_SHARD_VALUE(`t_order`, 42) */ * FROM t_order");
+        assert_eq!(Some(200), hints.get_segment());
+        assert_eq!(Some(&ShardValue::Int(42)), hints.get_shard_value("T_ORDER"));
+        assert!(!hints.is_primary());
+
+        let hints = route_hints("SELECT /*+ MARTLET_PRIMARY */ * FROM t_dept WHERE name = '/*+ x */'");
+        assert!(hints.is_primary());
+        assert_eq!(None, hints.get_segment());
+        assert!(!route_hints("SELECT * FROM t_dept").is_primary());
+        assert!(!route_hints("SELECT * FROM t_dept WHERE name = '/*+ MARTLET_PRIMARY */'").is_primary());
+        assert!(!route_hints("SELECT * FROM t_dept WHERE name = 'it''s /*+ MARTLET_PRIMARY */'").is_primary());
+        assert_eq!(None, route_hints("SELECT `/*+ MARTLET_SEGMENT(200) */` FROM t_dept").get_segment());
+        assert_eq!(None, route_hints("SELECT 1 -- /*+ MARTLET_SEGMENT(200) */").get_segment());
+    }
+
+    #[test]
+    fn test_strip_route_hints() {
+        assert_eq!("SELECT  * FROM t_order", strip_route_hints("SELECT /*+ MARTLET_SEGMENT(200) MARTLET_PRIMARY */ * FROM t_order"));
+        assert_eq!("SELECT /*+ MAX_EXECUTION_TIME(1000) BKA(t1) */ * FROM t1",
+                   strip_route_hints("SELECT /*+ MARTLET_SHARD_VALUE(t1, 'a b') MAX_EXECUTION_TIME(1000) martlet_primary BKA(t1) */ * FROM t1"));
+        let sql = "SELECT * FROM t_dept WHERE name = '/*+ MARTLET_PRIMARY */'";
+        assert_eq!(sql, strip_route_hints(sql));
+    }
+}
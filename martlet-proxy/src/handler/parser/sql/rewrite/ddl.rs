@@ -17,7 +17,7 @@ use std::fmt::Write;
 
 use sqlparser::ast::{AlterTableOperation, ColumnDef, ColumnOption, ColumnOptionDef, Ident, ReferentialAction, TableConstraint};
 
-use crate::handler::parser::sql::rewrite::{display_comma_separated, display_separated, SQLReWrite};
+use crate::handler::parser::sql::rewrite::{display_comma_separated, display_separated, physical_table_name, SQLReWrite};
 
 pub type SRWResult = martlet_common::common::Result<()>;
 
@@ -120,7 +120,7 @@ impl SQLReWrite for AlterTableOperation {
             }
             AlterTableOperation::RenameTable { table_name } => {
                 write!(f, "RENAME TO ")?;
-                table_name.rewrite(f, ctx)?;
+                physical_table_name(table_name).rewrite(f, ctx)?;
             }
         };
         Ok(())
@@ -165,7 +165,7 @@ impl SQLReWrite for TableConstraint {
                     f,
                     ") REFERENCES "
                 )?;
-                foreign_table.rewrite(f, ctx)?;
+                physical_table_name(foreign_table).rewrite(f, ctx)?;
                 write!(
                     f,
                     "("
@@ -254,7 +254,7 @@ impl SQLReWrite for ColumnOption {
                     f,
                     "REFERENCES "
                 )?;
-                foreign_table.rewrite(f, ctx)?;
+                physical_table_name(foreign_table).rewrite(f, ctx)?;
                 if !referred_columns.is_empty() {
                     write!(
                         f,
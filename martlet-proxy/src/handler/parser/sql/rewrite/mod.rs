@@ -79,6 +79,51 @@ impl SQLReWrite for Ident {
     }
 }
 
+/// Physical name of the logical table `name` after the rewrite context, which maps lowercase
+/// logical table names to physical `table` or `schema.table` names. A schema written before
+/// the logical table stays unless the physical name brings its own.
+fn physical_table(name: &[Ident], ctx: &HashMap<String, String>) -> Option<Vec<Ident>> {
+    let (table, schema) = name.split_last()?;
+    let physical = ctx.get(&table.value.to_lowercase())?;
+    let mut physical: Vec<Ident> = physical.split('.')
+        .map(|part| Ident { value: part.to_string(), quote_style: table.quote_style })
+        .collect();
+    if physical.len() == 1 {
+        physical.splice(0..0, schema.iter().cloned());
+    }
+    Some(physical)
+}
+
+/// A column's table qualifier after the rename of its table: `t_order` is `t_order_07` and
+/// `db.t_order` is `db_100.t_order_07`. Aliases and tables the context does not map stay.
+fn physical_qualifier(qualifier: &[Ident], ctx: &HashMap<String, String>) -> Vec<Ident> {
+    if qualifier.len() > 2 {
+        return qualifier.to_vec();
+    }
+    match physical_table(qualifier, ctx) {
+        Some(mut physical) if qualifier.len() == 1 => physical.split_off(physical.len() - 1),
+        Some(physical) => physical,
+        None => qualifier.to_vec(),
+    }
+}
+
+/// A table name, written as its physical table.
+struct TableName<'a>(&'a ObjectName);
+
+impl<'a> SQLReWrite for TableName<'a> {
+    fn rewrite(&self, f: &mut String, ctx: &HashMap<String, String>) -> SRWResult {
+        match physical_table(&(self.0).0, ctx) {
+            Some(physical) => display_separated(&physical, ".").rewrite(f, ctx)?,
+            None => self.0.rewrite(f, ctx)?,
+        }
+        Ok(())
+    }
+}
+
+fn physical_table_name(name: &ObjectName) -> TableName<'_> {
+    TableName(name)
+}
+
 impl SQLReWrite for ObjectName {
     fn rewrite(&self, f: &mut String, ctx: &HashMap<String, String>) -> SRWResult {
         display_separated(&self.0, ".").rewrite(f, ctx)?;
@@ -112,12 +157,17 @@ impl SQLReWrite for Expr {
                 f.write_str("*")?;
             }
             Expr::QualifiedWildcard(q) => {
-                display_separated(q, ".").rewrite(f, ctx)?;
+                display_separated(&physical_qualifier(q, ctx), ".").rewrite(f, ctx)?;
                 write!(f, ".*")?;
             }
-            Expr::CompoundIdentifier(s) => {
-                display_separated(s, ".").rewrite(f, ctx)?;
-            }
+            Expr::CompoundIdentifier(s) => match s.split_last() {
+                Some((column, qualifier)) if !qualifier.is_empty() => {
+                    display_separated(&physical_qualifier(qualifier, ctx), ".").rewrite(f, ctx)?;
+                    write!(f, ".")?;
+                    column.rewrite(f, ctx)?;
+                }
+                _ => display_separated(s, ".").rewrite(f, ctx)?,
+            },
             Expr::IsNull(ast) => {
                 ast.rewrite(f, ctx)?;
                 write!(f, " IS NULL")?;
@@ -288,7 +338,9 @@ impl SQLReWrite for Expr {
                 write!(f, ")")?;
             }
             Expr::TryCast { .. } => {} // TODO
-            Expr::ParameterMark(_) => {} // TODO
+            Expr::ParameterMark(_) => {
+                write!(f, "{}", self)?;
+            }
         };
         Ok(())
     }
@@ -446,7 +498,7 @@ impl SQLReWrite for Statement {
                 partitions,
             } => {
                 write!(f, "TRUNCATE TABLE ")?;
-                table_name.rewrite(f, ctx)?;
+                physical_table_name(table_name).rewrite(f, ctx)?;
                 if let Some(ref parts) = partitions {
                     if !parts.is_empty() {
                         write!(f, " PARTITION (")?;
@@ -457,7 +509,7 @@ impl SQLReWrite for Statement {
             }
             Statement::Analyze { table_name, partitions, for_columns, columns, cache_metadata, noscan, compute_statistics } => {
                 write!(f, "ANALYZE TABLE ")?;
-                table_name.rewrite(f, ctx)?;
+                physical_table_name(table_name).rewrite(f, ctx)?;
 
                 if let Some(ref parts) = partitions {
                     if !parts.is_empty() {
@@ -493,7 +545,7 @@ impl SQLReWrite for Statement {
                     write!(f, "INSERT OR ")?;
                     action.rewrite(f, ctx)?; // TODO
                     write!(f, " INTO ")?;
-                    table_name.rewrite(f, ctx)?;
+                    physical_table_name(table_name).rewrite(f, ctx)?;
                     write!(f, " ")?;
                 } else {
                     write!(
@@ -502,7 +554,7 @@ impl SQLReWrite for Statement {
                         act = if *overwrite { "OVERWRITE" } else { "INTO" },
                         tbl = if *table { " TABLE" } else { "" }
                     )?;
-                    table_name.rewrite(f, ctx)?;
+                    physical_table_name(table_name).rewrite(f, ctx)?;
                     write!(f, " ")?;
                 }
                 if !columns.is_empty() {
@@ -559,7 +611,7 @@ impl SQLReWrite for Statement {
                 limit,
             } => {
                 write!(f, "UPDATE ")?;
-                table_name.rewrite(f, ctx)?;
+                physical_table_name(table_name).rewrite(f, ctx)?;
                 if !assignments.is_empty() {
                     write!(f, " SET ")?;
                     display_comma_separated(assignments).rewrite(f, ctx)?;
@@ -578,7 +630,7 @@ impl SQLReWrite for Statement {
                 selection,
             } => {
                 write!(f, "DELETE FROM ")?;
-                table_name.rewrite(f, ctx)?;
+                physical_table_name(table_name).rewrite(f, ctx)?;
                 if let Some(selection) = selection {
                     write!(f, " WHERE ")?;
                     selection.rewrite(f, ctx)?;
@@ -658,7 +710,7 @@ impl SQLReWrite for Statement {
                     external = if *external { "EXTERNAL " } else { "" },
                     if_not_exists = if *if_not_exists { "IF NOT EXISTS " } else { "" },
                 )?;
-                name.rewrite(f, ctx)?;
+                physical_table_name(name).rewrite(f, ctx)?;
                 if !columns.is_empty() || !constraints.is_empty() {
                     write!(f, " (")?;
                     display_comma_separated(columns).rewrite(f, ctx)?;
@@ -849,7 +901,7 @@ impl SQLReWrite for Statement {
                     f,
                     " ON "
                 )?;
-                table_name.rewrite(f, ctx)?;
+                physical_table_name(table_name).rewrite(f, ctx)?;
                 write!(
                     f,
                     "("
@@ -862,7 +914,7 @@ impl SQLReWrite for Statement {
             }
             Statement::AlterTable { name, operation } => {
                 write!(f, "ALTER TABLE ")?;
-                name.rewrite(f, ctx)?;
+                physical_table_name(name).rewrite(f, ctx)?;
                 write!(f, " ")?;
                 operation.rewrite(f, ctx)?;
             }
@@ -882,7 +934,13 @@ impl SQLReWrite for Statement {
                     "{} ",
                     if *if_exists { " IF EXISTS" } else { "" }
                 )?;
-                display_comma_separated(names).rewrite(f, ctx)?;
+                match object_type {
+                    ObjectType::Table => {
+                        let names: Vec<TableName> = names.iter().map(physical_table_name).collect();
+                        display_comma_separated(&names).rewrite(f, ctx)?;
+                    }
+                    _ => display_comma_separated(names).rewrite(f, ctx)?,
+                }
                 write!(
                     f,
                     "{}{}",
@@ -931,7 +989,7 @@ impl SQLReWrite for Statement {
                     extended = if *extended { "EXTENDED " } else { "" },
                     full = if *full { "FULL " } else { "" }
                 )?;
-                table_name.rewrite(f, ctx)?;
+                physical_table_name(table_name).rewrite(f, ctx)?;
                 if let Some(filter) = filter {
                     write!(f, " ")?;
                     filter.rewrite(f, ctx)?;
@@ -1379,4 +1437,26 @@ mod tests {
         stmt.rewrite(&mut resql, &ctx).unwrap();
         assert_eq!(sql.to_uppercase(), resql.to_uppercase());
     }
+
+    #[test]
+    fn test_rewrite_physical_tables() {
+        let mut ctx: HashMap<String, String> = HashMap::new();
+        ctx.insert(String::from("t_order"), String::from("martlet_100.t_order_07"));
+        ctx.insert(String::from("t_order_item"), String::from("t_order_item_07"));
+        let rewrite = |sql: &str| {
+            let stmt = parser(sql.to_string()).pop().unwrap();
+            let mut resql = String::new();
+            stmt.rewrite(&mut resql, &ctx).unwrap();
+            resql
+        };
+        assert_eq!(rewrite("SELECT o.id, t_order_item.* FROM t_order AS o JOIN t_order_item ON o.id = t_order_item.order_id"),
+                   "SELECT o.id, t_order_item_07.* FROM martlet_100.t_order_07 AS o JOIN t_order_item_07 ON o.id = t_order_item_07.order_id");
+        assert_eq!(rewrite("UPDATE martlet.t_order SET status = 1 WHERE martlet.t_order.user_id = 1"),
+                   "UPDATE martlet_100.t_order_07 SET status = 1 WHERE martlet_100.t_order_07.user_id = 1");
+        assert_eq!(rewrite("DELETE FROM martlet.t_order_item WHERE t_order_item.id = 1"),
+                   "DELETE FROM martlet.t_order_item_07 WHERE t_order_item_07.id = 1");
+        assert_eq!(rewrite("DROP TABLE t_order, t_user"), "DROP TABLE martlet_100.t_order_07, t_user");
+        assert_eq!(rewrite("SELECT COUNT(*) FROM t_user"), "SELECT COUNT(*) FROM t_user");
+        assert_eq!(rewrite("INSERT INTO t_order (user_id, id) VALUES (?, ?)"), "INSERT INTO martlet_100.t_order_07 (user_id, id) VALUES (?, ?)");
+    }
 }
@@ -15,7 +15,7 @@ use std::fmt::Write;
 
 use sqlparser::ast::{Cte, Fetch, Join, JoinConstraint, JoinOperator, Offset, OffsetRows, OrderByExpr, Query, Select, SelectItem, SetExpr, SetOperator, TableAlias, TableFactor, TableWithJoins, Top, Values, With};
 
-use crate::handler::parser::sql::rewrite::{display_comma_separated, SQLReWrite};
+use crate::handler::parser::sql::rewrite::{display_comma_separated, physical_table_name, SQLReWrite};
 
 pub type SRWResult = martlet_common::common::Result<()>;
 
@@ -196,7 +196,7 @@ impl SQLReWrite for TableFactor {
                 args,
                 with_hints,
             } => {
-                name.rewrite(f, ctx)?;
+                physical_table_name(name).rewrite(f, ctx)?;
                 if !args.is_empty() {
                     write!(f, "(")?;
                     display_comma_separated(args).rewrite(f, ctx)?;
@@ -0,0 +1,136 @@
+use rhai::{Dynamic, Engine, ImmutableString, Scope};
+
+use crate::discovery::CompiledExpression;
+use crate::handler::parser::sql::route::hash::hash_key;
+use crate::handler::parser::sql::route::ShardingAlgorithm;
+use crate::handler::parser::sql::route::value::ShardValue;
+
+lazy_static! {
+    /// Evaluates the CUSTOM expressions, with the helpers they may call.
+    static ref ENGINE: Engine = custom_engine();
+}
+
+/// Helpers of CUSTOM expressions:
+///
+/// * `hash(v)`: the HASH algorithm's hash of an integer or string key, a non negative integer;
+/// * `mod(a, b)`: `a` modulo `b`, never negative;
+/// * `year(d)`, `month(d)`, `day(d)`, `hour(d)`: parts of a `'YYYY-MM-DD[ HH:MM:SS]'` date, 0 if it is none.
+fn custom_engine() -> Engine {
+    let mut engine = Engine::new();
+    engine.register_fn("hash", |value: i64| hash_key(&[ShardValue::Int(value)]) as i64);
+    engine.register_fn("hash", |value: ImmutableString| hash_key(&[ShardValue::from_text(value.as_bytes())]) as i64);
+    engine.register_fn("mod", |a: i64, b: i64| if b == 0 { 0 } else { a.rem_euclid(b) });
+    engine.register_fn("year", |date: ImmutableString| date_part(date.as_str(), 0, 4));
+    engine.register_fn("month", |date: ImmutableString| date_part(date.as_str(), 5, 7));
+    engine.register_fn("day", |date: ImmutableString| date_part(date.as_str(), 8, 10));
+    engine.register_fn("hour", |date: ImmutableString| date_part(date.as_str(), 11, 13));
+    engine
+}
+
+fn date_part(date: &str, from: usize, to: usize) -> i64 {
+    date.get(from..to).and_then(|part| part.parse().ok()).unwrap_or(0)
+}
+
+/// `DisType::CUSTOM`: evaluates the table's `dis_expression` with every `dis_keys` column bound
+/// to its value by name; the integer it returns is the data segment id.
+pub struct CustomAlgorithm {
+    dis_keys: Vec<String>,
+    expression: CompiledExpression,
+    segment_ids: Vec<u32>,
+}
+
+impl CustomAlgorithm {
+    pub fn new(dis_keys: &[String], expression: CompiledExpression, segment_ids: &[u32]) -> Self {
+        CustomAlgorithm {
+            dis_keys: dis_keys.to_vec(),
+            expression,
+            segment_ids: segment_ids.to_vec(),
+        }
+    }
+}
+
+impl ShardingAlgorithm for CustomAlgorithm {
+    fn shard(&self, values: &[ShardValue]) -> Option<u32> {
+        let mut scope = Scope::new();
+        for (dis_key, value) in self.dis_keys.iter().zip(values) {
+            let value = match value {
+                ShardValue::Null => Dynamic::from(()),
+                ShardValue::Int(i) => Dynamic::from(*i),
+                ShardValue::UInt(u) => Dynamic::from(ImmutableString::from(u.to_string())),
+                ShardValue::Text(bytes) => Dynamic::from(ImmutableString::from(String::from_utf8_lossy(bytes).to_string())),
+            };
+            scope.push_dynamic(dis_key.clone(), value);
+        }
+        match ENGINE.eval_ast_with_scope::<i64>(&mut scope, self.expression.get_ast()) {
+            Ok(id) if id >= 0 && self.segment_ids.contains(&(id as u32)) => Some(id as u32),
+            Ok(id) => {
+                println!("custom dis_expression returned {}, which is no data segment", id);
+                None
+            }
+            Err(e) => {
+                println!("error on evaluating custom dis_expression; error = {:?}", e);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::discovery::Cluster;
+    use crate::handler::parser::sql::route::custom::CustomAlgorithm;
+    use crate::handler::parser::sql::route::hash::hash_key;
+    use crate::handler::parser::sql::route::ShardingAlgorithm;
+    use crate::handler::parser::sql::route::value::ShardValue;
+
+    fn cluster(dis_expression: &str) -> Result<Cluster, String> {
+        Cluster::try_from_str(format!(r#"
+name: martlet
+segments:
+  meta_segment:
+    primary: {{ id: 0, url: "jdbc:mysql://localhost:3306/martlet", username: root, password: root }}
+    mirrors: [ ]
+  data_segments: {{ }}
+dis_rules:
+  distributed_tables:
+    t_order:
+      dis_keys: [ user_id, created ]
+      dis_algorithm: {{ dis_type: CUSTOM, dis_expression: "{}" }}
+      dis_relatives: [ ]
+  replicated_tables: [ ]
+"#, dis_expression).as_str())
+    }
+
+    fn algorithm(dis_expression: &str) -> CustomAlgorithm {
+        let cluster = cluster(dis_expression).unwrap();
+        let dis_table = cluster.get_dis_rules().get_distributed_table("t_order").unwrap();
+        CustomAlgorithm::new(dis_table.get_dis_keys(), dis_table.get_dis_algorithm().unwrap().get_compiled().unwrap().clone(), &[100, 200, 300])
+    }
+
+    #[test]
+    fn test_custom_algorithm() {
+        let date = ShardValue::Text(b"2021-03-04 05:06:07".to_vec());
+
+        let custom = algorithm("mod(user_id, 3) * 100 + 100");
+        assert_eq!(custom.shard(&[ShardValue::Int(4), date.clone()]), Some(200));
+        assert_eq!(custom.shard(&[ShardValue::Int(-1), date.clone()]), Some(300));
+
+        let custom = algorithm("if year(created) < 2021 { 100 } else { month(created) * 100 - 100 }");
+        assert_eq!(custom.shard(&[ShardValue::Int(4), date.clone()]), Some(200));
+        assert_eq!(custom.shard(&[ShardValue::Int(4), ShardValue::Text(b"2020-12-31".to_vec())]), Some(100));
+
+        let custom = algorithm("mod(hash(user_id), 3) * 100 + 100");
+        let expected = (hash_key(&[ShardValue::Int(42)]) % 3) * 100 + 100;
+        assert_eq!(custom.shard(&[ShardValue::Int(42), date.clone()]), Some(expected));
+
+        // 400 is no data segment.
+        let custom = algorithm("user_id * 100");
+        assert_eq!(custom.shard(&[ShardValue::Int(4), date]), None);
+    }
+
+    #[test]
+    fn test_bad_custom_expression() {
+        let e = cluster("mod(user_id, ").unwrap_err();
+        assert!(e.contains("t_order"), "{}", e);
+    }
+}
@@ -0,0 +1,162 @@
+use crate::handler::parser::sql::route::ShardingAlgorithm;
+use crate::handler::parser::sql::route::value::ShardValue;
+
+/// 32 bit MurmurHash3 (x86 variant), as published with SMHasher.
+pub fn murmur3_32(data: &[u8], seed: u32) -> u32 {
+    const C1: u32 = 0xcc9e_2d51;
+    const C2: u32 = 0x1b87_3593;
+
+    let mut h = seed;
+    let chunks = data.chunks_exact(4);
+    let tail = chunks.remainder();
+    for chunk in chunks {
+        let mut k = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        k = k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+        h ^= k;
+        h = h.rotate_left(13).wrapping_mul(5).wrapping_add(0xe654_6b64);
+    }
+    if !tail.is_empty() {
+        let mut k = 0u32;
+        for (i, b) in tail.iter().enumerate() {
+            k ^= (*b as u32) << (8 * i);
+        }
+        h ^= k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+    }
+
+    h ^= data.len() as u32;
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x85eb_ca6b);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0xc2b2_ae35);
+    h ^= h >> 16;
+    h
+}
+
+/// Hash of the sharding key: one key hashes its value's encoding, several keys the
+/// concatenation of their encodings, each prefixed with its length as 4 bytes little endian.
+pub fn hash_key(values: &[ShardValue]) -> u32 {
+    if values.len() == 1 {
+        return murmur3_32(values[0].encode().as_slice(), 0);
+    }
+    let mut data = Vec::new();
+    for value in values {
+        let encoded = value.encode();
+        data.extend_from_slice(&(encoded.len() as u32).to_le_bytes());
+        data.extend(encoded);
+    }
+    murmur3_32(data.as_slice(), 0)
+}
+
+/// Consistent-hash ring over the data segments. Every segment owns `virtual_nodes` points,
+/// the point of node `i` of segment `id` is the hash of `"SEGMENT-{id}-NODE-{i}"`; a key
+/// belongs to the segment of the first point at or after its hash, wrapping around.
+/// Adding a segment to N others moves about 1/(N+1) of the keys, all of them to the new segment.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HashRing {
+    points: Vec<(u32, u32)>,
+}
+
+impl HashRing {
+    pub fn new(segment_ids: &[u32], virtual_nodes: u32) -> Self {
+        let mut points = Vec::with_capacity(segment_ids.len() * virtual_nodes as usize);
+        for id in segment_ids {
+            for node in 0..virtual_nodes.max(1) {
+                let point = murmur3_32(format!("SEGMENT-{}-NODE-{}", id, node).as_bytes(), 0);
+                points.push((point, *id));
+            }
+        }
+        // Ties between segments go to the lower id, whatever order they were configured in.
+        points.sort();
+        points.dedup_by_key(|point| point.0);
+        HashRing {
+            points,
+        }
+    }
+
+    pub fn segment_of(&self, hash: u32) -> Option<u32> {
+        if self.points.is_empty() {
+            return None;
+        }
+        let index = match self.points.binary_search_by(|point| point.0.cmp(&hash)) {
+            Ok(index) => index,
+            Err(index) => index % self.points.len(),
+        };
+        Some(self.points[index].1)
+    }
+}
+
+/// `DisType::HASH`: the segment on the ring owning the key's hash.
+pub struct HashAlgorithm {
+    ring: HashRing,
+}
+
+impl HashAlgorithm {
+    pub fn new(segment_ids: &[u32], virtual_nodes: u32) -> Self {
+        HashAlgorithm {
+            ring: HashRing::new(segment_ids, virtual_nodes),
+        }
+    }
+}
+
+impl ShardingAlgorithm for HashAlgorithm {
+    fn shard(&self, values: &[ShardValue]) -> Option<u32> {
+        self.ring.segment_of(hash_key(values))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlparser::ast::Value;
+
+    use crate::handler::parser::sql::route::hash::{hash_key, HashRing, murmur3_32};
+    use crate::handler::parser::sql::route::value::ShardValue;
+    use crate::protocol::mysql::packet::binary::PrepareParamValue;
+
+    #[test]
+    fn test_murmur3() {
+        assert_eq!(murmur3_32(b"", 0), 0);
+        assert_eq!(murmur3_32(b"hello", 0), 0x248b_fa47);
+        assert_eq!(murmur3_32(b"The quick brown fox jumps over the lazy dog", 0), 0x2e4f_f723);
+    }
+
+    #[test]
+    fn test_literal_and_param_hash_alike() {
+        let pairs = vec![
+            (Value::Number(String::from("42"), false), PrepareParamValue::Int(42)),
+            (Value::Number(String::from("42.0"), false), PrepareParamValue::UInt(42)),
+            (Value::SingleQuotedString(String::from("42")), PrepareParamValue::Int(42)),
+            (Value::Number(String::from("1.50"), false), PrepareParamValue::Double(1.5)),
+            (Value::SingleQuotedString(String::from("abc")), PrepareParamValue::Bytes(b"abc".to_vec())),
+            (Value::SingleQuotedString(String::from("2021-03-04")), PrepareParamValue::Date(2021, 3, 4, 0, 0, 0, 0)),
+            (Value::SingleQuotedString(String::from("2021-03-04 05:06:07")), PrepareParamValue::Date(2021, 3, 4, 5, 6, 7, 0)),
+        ];
+        for (literal, param) in pairs {
+            let literal = ShardValue::from_literal(&literal).unwrap();
+            let param = ShardValue::from_param(&param);
+            assert_eq!(literal, param);
+            assert_eq!(hash_key(&[literal]), hash_key(&[param]));
+        }
+        assert_ne!(ShardValue::from_literal(&Value::SingleQuotedString(String::from("042"))), Some(ShardValue::Int(42)));
+    }
+
+    #[test]
+    fn test_hash_ring() {
+        let ring = HashRing::new(&[100, 200, 300], 160);
+        let grown = HashRing::new(&[100, 200, 300, 400], 160);
+        let mut moved = 0;
+        let mut owned = [0; 3];
+        for key in 0..10000i64 {
+            let hash = hash_key(&[ShardValue::Int(key)]);
+            let before = ring.segment_of(hash).unwrap();
+            let after = grown.segment_of(hash).unwrap();
+            owned[(before / 100 - 1) as usize] += 1;
+            if before != after {
+                assert_eq!(after, 400);
+                moved += 1;
+            }
+        }
+        assert!(moved > 1500 && moved < 3500, "moved {}", moved);
+        assert!(owned.iter().all(|n| *n > 2500), "owned {:?}", owned);
+        assert_eq!(HashRing::new(&[300, 100, 200], 160), ring);
+    }
+}
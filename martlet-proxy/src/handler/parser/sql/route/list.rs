@@ -0,0 +1,112 @@
+use std::collections::HashMap;
+
+use crate::discovery::DisList;
+use crate::handler::parser::sql::route::ShardingAlgorithm;
+use crate::handler::parser::sql::route::value::ShardValue;
+
+/// `DisType::LIST`: a key lives on the segment of the list naming its value, or on the
+/// default segment if no list does.
+pub struct ListAlgorithm {
+    segments: HashMap<ShardValue, u32>,
+    default_segment: Option<u32>,
+}
+
+impl ListAlgorithm {
+    pub fn new(lists: &[DisList], default_segment: Option<u32>) -> Self {
+        let mut segments = HashMap::new();
+        for list in lists {
+            for value in list.get_values() {
+                segments.insert(ShardValue::from_dis_value(value), list.get_segment());
+            }
+        }
+        ListAlgorithm {
+            segments,
+            default_segment,
+        }
+    }
+}
+
+impl ShardingAlgorithm for ListAlgorithm {
+    fn shard(&self, values: &[ShardValue]) -> Option<u32> {
+        let value = values.first()?;
+        self.segments.get(value).cloned().or(self.default_segment)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::discovery::{Cluster, DisList};
+    use crate::handler::parser::sql::analyse::SQLAnalyse;
+    use crate::handler::parser::sql::mysql::{parser, RouteHints};
+    use crate::handler::parser::sql::route::{route, RouteContext, ShardingAlgorithm};
+    use crate::handler::parser::sql::route::list::ListAlgorithm;
+    use crate::handler::parser::sql::route::value::ShardValue;
+    use crate::handler::parser::sql::SQLStatementContext;
+
+    const CLUSTER: &str = r#"
+name: martlet
+segments:
+  meta_segment:
+    primary: { id: 0, url: "jdbc:mysql://localhost:3306/martlet", username: root, password: root }
+    mirrors: [ ]
+  data_segments:
+    100:
+      primary: { id: 0, url: "jdbc:mysql://localhost:3306/martlet_100", username: root, password: root }
+      mirrors: [ ]
+    200:
+      primary: { id: 0, url: "jdbc:mysql://localhost:3306/martlet_200", username: root, password: root }
+      mirrors: [ ]
+    300:
+      primary: { id: 0, url: "jdbc:mysql://localhost:3306/martlet_300", username: root, password: root }
+      mirrors: [ ]
+dis_rules:
+  distributed_tables:
+    t_tenant:
+      dis_keys: [ region ]
+      dis_algorithm:
+        dis_type: LIST
+        dis_expression: ""
+        lists:
+          - { values: [ CN, HK ], segment: 100 }
+          - { values: [ US, CA ], segment: 200 }
+        default_segment: 300
+      dis_relatives: [ ]
+  replicated_tables: [ ]
+"#;
+
+    fn text(s: &str) -> ShardValue {
+        ShardValue::Text(s.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn test_list_algorithm() {
+        let lists: Vec<DisList> = serde_yaml::from_str("[ { values: [ 1, 2 ], segment: 100 }, { values: [ 3 ], segment: 200 } ]").unwrap();
+        let list = ListAlgorithm::new(lists.as_slice(), None);
+        assert_eq!(list.shard(&[ShardValue::Int(2)]), Some(100));
+        assert_eq!(list.shard(&[ShardValue::Int(3)]), Some(200));
+        assert_eq!(list.shard(&[ShardValue::Int(4)]), None);
+
+        let route_ctx = RouteContext::new(Arc::new(Cluster::from_str(CLUSTER)));
+        let algorithm = route_ctx.get_algorithm("t_tenant").unwrap();
+        assert_eq!(algorithm.shard(&[text("HK")]), Some(100));
+        assert_eq!(algorithm.shard(&[text("FR")]), Some(300));
+
+        let groups = |sql: &str| {
+            let statement = parser(sql.to_string()).pop().unwrap();
+            let mut stmt_ctx = SQLStatementContext::new(&statement);
+            statement.analyse(&mut stmt_ctx).unwrap();
+            route(&route_ctx, &stmt_ctx, sql, &[], &[], &RouteHints::default()).unwrap().get_groups().len()
+        };
+        assert_eq!(groups("SELECT * FROM t_tenant WHERE region = 'CN'"), 1);
+        assert_eq!(groups("SELECT * FROM t_tenant WHERE region IN ('CN', 'HK', 'US')"), 2);
+        assert_eq!(groups("SELECT * FROM t_tenant WHERE region > 'CN'"), 3);
+    }
+
+    #[test]
+    fn test_duplicate_list_value() {
+        let e = Cluster::try_from_str(CLUSTER.replace("[ US, CA ]", "[ US, HK ]").as_str()).unwrap_err();
+        assert!(e.contains("t_tenant"), "{}", e);
+    }
+}
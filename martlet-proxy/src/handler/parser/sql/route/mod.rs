@@ -1,8 +1,1302 @@
-// use crate::discovery::Cluster;
-// use crate::handler::mysql::explainplan::{ExplainPlanContext, ExplainPlan};
-// use crate::handler::parser::sql::SQLStatementContext;
-
-// pub fn route(route_ctx: RouteContext,
-//              stmt_ctx: SQLStatementContext) -> ExplainPlan {
-//
-// }
\ No newline at end of file
+use std::collections::{BTreeSet, HashMap};
+use std::mem;
+use std::sync::{Arc, RwLock};
+
+use sqlparser::ast::{SetExpr, Statement};
+
+use crate::discovery::{Cluster, DisRules, DisTable, DisType, KeyUpdate, SegmentGroup};
+use crate::error::ProxyError;
+use crate::handler::parser::sql::condition::{ConditionValue, ShardingCondition, ShardingConditions};
+use crate::handler::parser::sql::mysql::RouteHints;
+use crate::handler::parser::sql::route::custom::CustomAlgorithm;
+use crate::handler::parser::sql::route::hash::{hash_key, HashAlgorithm};
+use crate::handler::parser::sql::route::list::ListAlgorithm;
+use crate::handler::parser::sql::route::range::RangeAlgorithm;
+use crate::handler::parser::sql::route::value::ShardValue;
+use crate::handler::parser::sql::SQLStatementContext;
+
+pub mod custom;
+pub mod hash;
+pub mod list;
+pub mod range;
+pub mod value;
+
+/// Point lookups expanded from the conditions of several sharding keys are capped, beyond
+/// this the table is routed to every segment.
+const MAX_POINT_LOOKUPS: usize = 64;
+
+/// Maps the values of a distributed table's `dis_keys` to the id of the data segment holding the row.
+pub trait ShardingAlgorithm: Send + Sync {
+    /// Segment of the row whose keys have the values, in `dis_keys` order.
+    fn shard(&self, values: &[ShardValue]) -> Option<u32>;
+
+    /// Segments which may hold rows whose single key lies in the range, `None` for all of them.
+    fn shard_range(&self, _low: Option<(&ShardValue, bool)>, _high: Option<(&ShardValue, bool)>) -> Option<Vec<u32>> {
+        None
+    }
+}
+
+/// The cluster layout and distribution rules a statement is routed against: those of a
+/// configured schema over its data segments, or the cluster's own over all of them.
+pub struct RouteContext {
+    cluster: Arc<Cluster>,
+    schema: Option<String>,
+    algorithms: HashMap<String, Arc<dyn ShardingAlgorithm>>,
+}
+
+lazy_static! {
+    static ref ROUTE_CONTEXTS: RwLock<HashMap<Option<String>, Arc<RouteContext>>> = RwLock::new(HashMap::new());
+}
+
+impl RouteContext {
+    pub fn new(cluster: Arc<Cluster>) -> Self {
+        RouteContext::with_schema(cluster, None)
+    }
+
+    /// Context of the configured schema's rules, of the cluster's own for `None`.
+    pub fn with_schema(cluster: Arc<Cluster>, schema: Option<String>) -> Self {
+        let mut route_ctx = RouteContext {
+            cluster,
+            schema,
+            algorithms: HashMap::new(),
+        };
+        let segment_ids: Vec<u32> = route_ctx.get_data_groups().into_iter()
+            .filter_map(|group| match group {
+                SegmentGroup::Data(id) => Some(id),
+                SegmentGroup::Meta => None,
+            })
+            .collect();
+        let mut algorithms: HashMap<String, Arc<dyn ShardingAlgorithm>> = HashMap::new();
+        let dis_tables = route_ctx.get_dis_rules().get_distributed_tables();
+        for (table, dis_table) in dis_tables {
+            let dis_algorithm = match dis_table.get_dis_algorithm() {
+                Some(dis_algorithm) => dis_algorithm,
+                None => continue,
+            };
+            let algorithm: Arc<dyn ShardingAlgorithm> = match dis_algorithm.get_dis_type() {
+                DisType::HASH => Arc::new(HashAlgorithm::new(segment_ids.as_slice(), dis_algorithm.get_virtual_nodes())),
+                DisType::RANGE => Arc::new(RangeAlgorithm::new(dis_algorithm.get_ranges().as_slice())),
+                DisType::LIST => Arc::new(ListAlgorithm::new(dis_algorithm.get_lists().as_slice(), dis_algorithm.get_default_segment())),
+                DisType::CUSTOM => match dis_algorithm.get_compiled() {
+                    Some(compiled) => Arc::new(CustomAlgorithm::new(dis_table.get_dis_keys(), compiled.clone(), segment_ids.as_slice())),
+                    None => continue,
+                },
+            };
+            algorithms.insert(table.to_lowercase(), algorithm);
+        }
+        // Relatives share their parent's algorithm, fed with their own keys in the same order.
+        for (table, dis_table) in dis_tables {
+            if let Some(parent) = dis_table.get_dis_parent() {
+                if let Some(algorithm) = algorithms.get(&parent.to_lowercase()).cloned() {
+                    algorithms.insert(table.to_lowercase(), algorithm);
+                }
+            }
+        }
+        route_ctx.algorithms = algorithms;
+        route_ctx
+    }
+
+    /// Context of the current cluster, rebuilt when the cluster changed.
+    pub fn current() -> Arc<RouteContext> {
+        RouteContext::current_of(None)
+    }
+
+    /// Context of the configured schema in the current cluster, see `statement_schema`.
+    pub fn current_of(schema: Option<String>) -> Arc<RouteContext> {
+        let cluster = Cluster::current();
+        if let Some(route_ctx) = ROUTE_CONTEXTS.read().unwrap().get(&schema) {
+            if Arc::ptr_eq(&route_ctx.cluster, &cluster) {
+                return route_ctx.clone();
+            }
+        }
+        let route_ctx = Arc::new(RouteContext::with_schema(cluster.clone(), schema.clone()));
+        let mut route_ctxs = ROUTE_CONTEXTS.write().unwrap();
+        route_ctxs.retain(|_, other| Arc::ptr_eq(&other.cluster, &cluster));
+        route_ctxs.insert(schema, route_ctx.clone());
+        route_ctx
+    }
+
+    pub fn get_cluster(&self) -> &Cluster {
+        &self.cluster
+    }
+
+    /// The configured schema routed for, `None` for the meta segment's database.
+    pub fn get_schema(&self) -> Option<&String> {
+        self.schema.as_ref()
+    }
+
+    pub fn get_dis_rules(&self) -> &DisRules {
+        self.cluster.get_schema_dis_rules(self.schema.as_ref().map(|schema| schema.as_str()))
+    }
+
+    /// Algorithm of the distributed table, `None` if its rows cannot be located by key.
+    pub fn get_algorithm(&self, table: &str) -> Option<&dyn ShardingAlgorithm> {
+        self.algorithms.get(&table.to_lowercase()).map(|algorithm| algorithm.as_ref())
+    }
+
+    /// Data segment groups of the schema, ordered by segment id.
+    pub fn get_data_groups(&self) -> Vec<SegmentGroup> {
+        let schema = self.schema.as_ref().and_then(|schema| self.cluster.get_schema(schema.as_str()));
+        self.cluster.get_segments().get_groups().into_iter()
+            .filter(|group| match (group, schema) {
+                (SegmentGroup::Meta, _) => false,
+                (SegmentGroup::Data(id), Some((_, schema))) => schema.get_data_segments().contains(id),
+                (SegmentGroup::Data(_), None) => true,
+            })
+            .collect()
+    }
+}
+
+/// The configured schema whose rules route the statement: the one its tables are qualified
+/// with, or else the session's database. Tables of several schemas cannot be routed together.
+pub fn statement_schema(cluster: &Cluster, stmt_ctx: &SQLStatementContext, database: &str) -> Result<Option<String>, ProxyError> {
+    let ruled = |database: &str| cluster.get_schema(database).map(|(name, _)| name.clone());
+    let mut schemas: Vec<Option<String>> = Vec::new();
+    for table in stmt_ctx.get_tables() {
+        let mut parts = table.rsplitn(2, '.');
+        parts.next();
+        let schema = match parts.next() {
+            Some(qualifier) => ruled(qualifier.trim_matches(|c| c == '`' || c == '"')),
+            None => ruled(database),
+        };
+        if !schemas.contains(&schema) {
+            schemas.push(schema);
+        }
+    }
+    match schemas.len() {
+        0 => Ok(ruled(database)),
+        1 => Ok(schemas.pop().unwrap()),
+        _ => Err(ProxyError::Unsupported(String::from("statements over the tables of several schemas"))),
+    }
+}
+
+/// How a logical table is laid out over the segments.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TableKind {
+    /// Rows are spread over the data segments by the table's `dis_keys`.
+    Distributed,
+    /// Every data segment holds a full copy.
+    Replicated,
+    /// Lives on one segment only, the meta segment unless configured otherwise.
+    Single,
+}
+
+/// The segment groups one logical table of the statement is routed to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TableRoute {
+    table: String,
+    kind: TableKind,
+    groups: Vec<SegmentGroup>,
+}
+
+impl TableRoute {
+    pub fn get_table(&self) -> &String {
+        &self.table
+    }
+
+    pub fn get_kind(&self) -> TableKind {
+        self.kind
+    }
+
+    pub fn get_groups(&self) -> &Vec<SegmentGroup> {
+        &self.groups
+    }
+}
+
+/// One physical SQL to run on one segment group.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RouteUnit {
+    group: SegmentGroup,
+    sql: String,
+    /// The `VALUES` rows of an INSERT split by segment this unit keeps, `None` for all of them.
+    rows: Option<Vec<usize>>,
+    /// Physical table of each distributed table split into several on the segment group, by
+    /// lowercase logical name.
+    physical_tables: HashMap<String, String>,
+}
+
+impl RouteUnit {
+    pub fn new(group: SegmentGroup, sql: String) -> Self {
+        RouteUnit {
+            group,
+            sql,
+            rows: None,
+            physical_tables: HashMap::new(),
+        }
+    }
+
+    pub fn with_rows(group: SegmentGroup, sql: String, rows: Vec<usize>) -> Self {
+        RouteUnit {
+            group,
+            sql,
+            rows: Some(rows),
+            physical_tables: HashMap::new(),
+        }
+    }
+
+    pub fn get_group(&self) -> SegmentGroup {
+        self.group
+    }
+
+    pub fn get_sql(&self) -> &String {
+        &self.sql
+    }
+
+    pub fn get_rows(&self) -> Option<&Vec<usize>> {
+        self.rows.as_ref()
+    }
+
+    pub fn get_physical_tables(&self) -> &HashMap<String, String> {
+        &self.physical_tables
+    }
+}
+
+/// Rows an UPDATE of a distributed table's sharding keys moves to the segment of their new keys.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyMove {
+    /// The table as the statement names it.
+    table: String,
+    dis_keys: Vec<String>,
+    /// New values of `dis_keys`.
+    values: Vec<ShardValue>,
+    target: SegmentGroup,
+}
+
+impl KeyMove {
+    pub fn get_table(&self) -> &String {
+        &self.table
+    }
+
+    pub fn get_dis_keys(&self) -> &Vec<String> {
+        &self.dis_keys
+    }
+
+    pub fn get_values(&self) -> &Vec<ShardValue> {
+        &self.values
+    }
+
+    pub fn get_target(&self) -> SegmentGroup {
+        self.target
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct RouteResult {
+    tables: Vec<TableRoute>,
+    units: Vec<RouteUnit>,
+    key_move: Option<KeyMove>,
+}
+
+impl RouteResult {
+    pub fn get_tables(&self) -> &Vec<TableRoute> {
+        &self.tables
+    }
+
+    pub fn get_units(&self) -> &Vec<RouteUnit> {
+        &self.units
+    }
+
+    pub fn get_groups(&self) -> Vec<SegmentGroup> {
+        self.units.iter().map(|unit| unit.group).collect()
+    }
+
+    /// The move of the rows whose sharding keys the UPDATE assigns, run after the units.
+    pub fn get_key_move(&self) -> Option<&KeyMove> {
+        self.key_move.as_ref()
+    }
+}
+
+/// Table name without schema and quotes, as the distribution rules name it.
+pub fn logical_table_name(name: &str) -> String {
+    let name = name.rsplit('.').next().unwrap_or(name);
+    name.trim_matches(|c| c == '`' || c == '"').to_string()
+}
+
+/// Decides the segment groups of every logical table the analysed statement touches and
+/// returns the physical SQL units to run, one per segment group. `parameters` are the values
+/// of the statement's `?`s, empty for the text protocol; `session_groups` are the segment
+/// groups the session is pinned to, the one it was pinned to last first.
+///
+/// Distributed tables are routed to the data segments holding their rows and everything
+/// else not replicated goes to the meta segment, or the data segment configured for it; the
+/// rows of an `INSERT ... VALUES` into a distributed table are split into one unit per
+/// segment. A distributed table split into physical tables on a segment gets a unit for each
+/// of them which may hold its rows, see `DisTable::get_physical_tables`. Replicated tables are
+/// written on every data segment; they are read on each segment the distributed tables of the
+/// query are, so joins with them run there, or else from one data segment, the session's own
+/// if it has one. A statement without tables, like `SELECT LAST_INSERT_ID()`, runs where the
+/// session was pinned last, so it sees that connection's state, or else on the meta segment.
+///
+/// `hints` override all of that: a hinted shard value routes its table as a condition on its
+/// sharding key would, and a hinted segment runs the whole statement there, its rows unsplit.
+///
+/// An INSERT or REPLACE ... SELECT into a distributed table is refused unless a hint pins it to
+/// one segment: its rows are only known once selected, too late to split them.
+///
+/// An UPDATE assigning the `dis_keys` of a distributed table is refused, unless the table's
+/// `key_update` is MOVE: it then also runs on the segment of the new keys, which the rows it
+/// updates elsewhere are moved to.
+pub fn route(route_ctx: &RouteContext, stmt_ctx: &SQLStatementContext, sql: &str, parameters: &[ShardValue], session_groups: &[SegmentGroup], hints: &RouteHints) -> Result<RouteResult, ProxyError> {
+    let dis_rules = route_ctx.get_dis_rules();
+    let is_read = match stmt_ctx {
+        SQLStatementContext::Select(_) => true,
+        _ => false,
+    };
+
+    let mut table_names: Vec<String> = stmt_ctx.get_tables().iter().map(|table| logical_table_name(table)).collect();
+    table_names.sort();
+    table_names.dedup();
+
+    let mut tables = Vec::new();
+    let mut row_groups: Option<Vec<SegmentGroup>> = None;
+    let mut row_hashes: Vec<u32> = Vec::new();
+    for table in table_names {
+        let (kind, groups) = match dis_rules.get_distributed_table(table.as_str()) {
+            Some(dis_table) if hints.get_shard_value(table.as_str()).is_some() => {
+                let value = hints.get_shard_value(table.as_str()).unwrap();
+                (TableKind::Distributed, vec![hinted_group(route_ctx, table.as_str(), dis_table, value)?])
+            }
+            Some(dis_table) if !stmt_ctx.get_insert_rows().is_empty() => {
+                let (groups, hashes): (Vec<SegmentGroup>, Vec<u32>) = insert_row_groups(route_ctx, table.as_str(), dis_table, stmt_ctx, parameters)?
+                    .into_iter()
+                    .unzip();
+                row_hashes = hashes;
+                let table_groups = route_ctx.get_data_groups().into_iter().filter(|group| groups.contains(group)).collect();
+                row_groups = Some(groups);
+                (TableKind::Distributed, table_groups)
+            }
+            Some(_) if hints.get_segment().is_none() && is_insert_target(stmt_ctx, table.as_str()) => {
+                return Err(ProxyError::Unsupported(format!("INSERT or REPLACE ... SELECT into the distributed table {} without a MARTLET_SHARD_VALUE or MARTLET_SEGMENT hint", table)));
+            }
+            Some(dis_table) => (TableKind::Distributed, shard_groups(route_ctx, table.as_str(), dis_table, stmt_ctx, parameters)),
+            None if dis_rules.is_replicated_table(table.as_str()) => (TableKind::Replicated, route_ctx.get_data_groups()),
+            None => (TableKind::Single, vec![dis_rules.get_single_group(table.as_str())]),
+        };
+        tables.push(TableRoute {
+            table,
+            kind,
+            groups,
+        });
+    }
+
+    let key_move = update_key_move(route_ctx, stmt_ctx, parameters, tables.as_slice())?;
+    bind_relatives(route_ctx, stmt_ctx, tables.as_mut_slice());
+    if is_read {
+        read_replicas(route_ctx, session_groups, tables.as_mut_slice());
+    }
+    if hints.get_segment().is_none() {
+        colocate_single_tables(tables.as_mut_slice())?;
+    }
+
+    // Distributed tables decide where the statement runs, the replicated ones are
+    // available wherever that is.
+    let mut groups: Vec<SegmentGroup> = Vec::new();
+    for kind in &[TableKind::Distributed, TableKind::Replicated, TableKind::Single] {
+        for table in tables.iter().filter(|table| table.kind == *kind) {
+            for group in &table.groups {
+                if !groups.contains(group) {
+                    groups.push(*group);
+                }
+            }
+        }
+        if !groups.is_empty() {
+            break;
+        }
+    }
+    if groups.is_empty() {
+        groups.push(session_groups.first().cloned().unwrap_or(SegmentGroup::Meta));
+    }
+    if let Some(id) = hints.get_segment() {
+        let group = SegmentGroup::Data(id);
+        if !route_ctx.get_data_groups().contains(&group) {
+            return Err(ProxyError::Unsupported(format!("MARTLET_SEGMENT({}), which is no data segment", id)));
+        }
+        for table in tables.iter_mut() {
+            table.groups = vec![group];
+        }
+        groups = vec![group];
+        row_groups = None;
+    }
+    if let Some(key_move) = &key_move {
+        if !groups.contains(&key_move.target) {
+            groups.push(key_move.target);
+        }
+    }
+
+    let mut units = Vec::new();
+    for group in groups {
+        let group_rows: Option<Vec<usize>> = row_groups.as_ref()
+            .map(|row_groups| (0..row_groups.len()).filter(|row| row_groups[*row] == group).collect());
+        let (split, indexes) = physical_split(route_ctx, stmt_ctx, parameters, hints, tables.as_slice(), group)?;
+        if split.is_empty() {
+            units.push(match group_rows {
+                Some(rows) if rows.len() < row_hashes.len() => RouteUnit::with_rows(group, sql.to_string(), rows),
+                _ => RouteUnit::new(group, sql.to_string()),
+            });
+            continue;
+        }
+        // A row of the INSERT goes to the physical table at its keys' hash.
+        let count = split[0].1.len();
+        for index in indexes {
+            let mut unit = if row_hashes.is_empty() {
+                RouteUnit::new(group, sql.to_string())
+            } else {
+                let rows: Vec<usize> = group_rows.clone().unwrap_or_else(|| (0..row_hashes.len()).collect()).into_iter()
+                    .filter(|row| row_hashes[*row] as usize % count == index)
+                    .collect();
+                match rows.len() {
+                    0 => continue,
+                    len if len == row_hashes.len() => RouteUnit::new(group, sql.to_string()),
+                    _ => RouteUnit::with_rows(group, sql.to_string(), rows),
+                }
+            };
+            unit.physical_tables = split.iter()
+                .map(|(table, physical)| (table.to_lowercase(), physical[index].clone()))
+                .collect();
+            units.push(unit);
+        }
+    }
+    Ok(RouteResult {
+        tables,
+        units,
+        key_move,
+    })
+}
+
+/// The move of an UPDATE assigning the sharding keys of a distributed table, `None` if it
+/// assigns none. Moving needs the table's `key_update` to be MOVE and every new key known.
+fn update_key_move(route_ctx: &RouteContext, stmt_ctx: &SQLStatementContext, parameters: &[ShardValue], tables: &[TableRoute]) -> Result<Option<KeyMove>, ProxyError> {
+    let assignments = stmt_ctx.get_assignments();
+    if assignments.is_empty() {
+        return Ok(None);
+    }
+    for table in tables.iter().filter(|table| table.kind == TableKind::Distributed) {
+        let dis_table = match route_ctx.get_dis_rules().get_distributed_table(table.table.as_str()) {
+            Some(dis_table) => dis_table,
+            None => continue,
+        };
+        let assigned = |dis_key: &String| assignments.iter().find(|(column, _)| column.eq_ignore_ascii_case(dis_key));
+        let dis_key = match dis_table.get_dis_keys().iter().find(|dis_key| assigned(dis_key).is_some()) {
+            Some(dis_key) => dis_key,
+            None => continue,
+        };
+        if *dis_table.get_key_update() != KeyUpdate::MOVE {
+            return Err(ProxyError::Unsupported(format!("UPDATE of dis_key {} of {}, which would leave its rows on the wrong segment", dis_key, table.table)));
+        }
+        let mut values = Vec::new();
+        for dis_key in dis_table.get_dis_keys() {
+            let value = assigned(dis_key)
+                .and_then(|(_, value)| value.as_ref())
+                .and_then(|value| shard_value(value, parameters))
+                .ok_or_else(|| ProxyError::Unsupported(format!("UPDATE moving rows of {} without a value for its dis_key {}", table.table, dis_key)))?;
+            values.push(value);
+        }
+        let target = route_ctx.get_algorithm(table.table.as_str())
+            .and_then(|algorithm| algorithm.shard(values.as_slice()))
+            .map(SegmentGroup::Data)
+            .ok_or_else(|| ProxyError::Unsupported(format!("UPDATE moving rows of {} to no data segment", table.table)))?;
+        let written = stmt_ctx.get_tables().into_iter()
+            .find(|written| logical_table_name(written).eq_ignore_ascii_case(table.table.as_str()))
+            .unwrap_or_else(|| table.table.clone());
+        return Ok(Some(KeyMove {
+            table: written,
+            dis_keys: dis_table.get_dis_keys().clone(),
+            values,
+            target,
+        }));
+    }
+    Ok(None)
+}
+
+/// Data segment group of the distributed table's rows whose single sharding key has the
+/// hinted value.
+fn hinted_group(route_ctx: &RouteContext, table: &str, dis_table: &DisTable, value: &ShardValue) -> Result<SegmentGroup, ProxyError> {
+    let algorithm = match route_ctx.get_algorithm(table) {
+        Some(algorithm) if dis_table.get_dis_keys().len() == 1 => algorithm,
+        _ => return Err(ProxyError::Unsupported(format!("MARTLET_SHARD_VALUE of {}, which has no single sharding key", table))),
+    };
+    algorithm.shard(&[value.clone()])
+        .map(SegmentGroup::Data)
+        .ok_or_else(|| ProxyError::Unsupported(format!("MARTLET_SHARD_VALUE of {}, whose value maps to no data segment", table)))
+}
+
+/// Data segment group of each row of an `INSERT ... VALUES` into the distributed table, with
+/// the hash of its keys.
+///
+/// The `dis_keys` are found in the rows by the INSERT's column list: the proxy knows no column
+/// order of the tables, so an INSERT listing no columns is refused unless a MARTLET_SHARD_VALUE
+/// hint routes its rows.
+fn insert_row_groups(route_ctx: &RouteContext, table: &str, dis_table: &DisTable, stmt_ctx: &SQLStatementContext, parameters: &[ShardValue]) -> Result<Vec<(SegmentGroup, u32)>, ProxyError> {
+    let algorithm = match route_ctx.get_algorithm(table) {
+        Some(algorithm) if !dis_table.get_dis_keys().is_empty() => algorithm,
+        _ => return Err(ProxyError::Unsupported(format!("INSERT into {}, whose rows cannot be located by key", table))),
+    };
+    let columns = stmt_ctx.get_insert_columns();
+    if columns.is_empty() {
+        return Err(ProxyError::Unsupported(format!("INSERT into {} without a column list naming its dis_keys", table)));
+    }
+    let mut key_indexes = Vec::new();
+    for dis_key in dis_table.get_dis_keys() {
+        let index = columns.iter().position(|column| column.eq_ignore_ascii_case(dis_key))
+            .ok_or_else(|| ProxyError::Unsupported(format!("INSERT into {} without a value for its dis_key {}", table, dis_key)))?;
+        key_indexes.push(index);
+    }
+    let mut groups = Vec::new();
+    for (number, row) in stmt_ctx.get_insert_rows().iter().enumerate() {
+        let values: Option<Vec<ShardValue>> = key_indexes.iter()
+            .map(|index| match row.get_values().get(*index) {
+                Some(Some(value)) => shard_value(value, parameters),
+                _ => None,
+            })
+            .collect();
+        let row = values.and_then(|values| algorithm.shard(values.as_slice()).map(|id| (SegmentGroup::Data(id), hash_key(values.as_slice()))))
+            .ok_or_else(|| ProxyError::Unsupported(format!("INSERT into {} whose row {} maps to no data segment", table, number + 1)))?;
+        groups.push(row);
+    }
+    Ok(groups)
+}
+
+/// The INSERT keeping only its `VALUES` rows at the indexes.
+pub fn split_insert(statement: &Statement, rows: &[usize]) -> Statement {
+    let mut statement = statement.clone();
+    if let Statement::Insert { source, .. } = &mut statement {
+        if let SetExpr::Values(values) = &mut source.body {
+            let all = mem::replace(&mut values.0, vec![]);
+            values.0 = all.into_iter().enumerate()
+                .filter(|(index, _)| rows.contains(index))
+                .map(|(_, row)| row)
+                .collect();
+        }
+    }
+    statement
+}
+
+/// Physical names of the statement's tables in the unit, its `SQLReWrite` context: the unit's
+/// physical tables, and tables named with the logical schema, the meta segment's database or
+/// the configured schema routed for, qualified with its database on the segment instead: the
+/// segment's own for the meta segment's database, the schema's `database` for a schema.
+/// Empty when the statement runs as it is, as on the meta segment, which holds the single
+/// tables of a configured schema in a database of its name.
+pub fn physical_tables(route_ctx: &RouteContext, stmt_ctx: &SQLStatementContext, unit: &RouteUnit) -> HashMap<String, String> {
+    let group = unit.get_group();
+    let mut physical = unit.get_physical_tables().clone();
+    let cluster = route_ctx.get_cluster();
+    let segments = cluster.get_segments();
+    let (logical_schema, database) = match route_ctx.get_schema() {
+        Some(_) if group == SegmentGroup::Meta => return physical,
+        Some(schema) => match cluster.get_schema(schema.as_str()) {
+            Some((name, schema)) => (name.clone(), schema.get_database().clone()),
+            None => return physical,
+        },
+        None => match segments.get_group(group) {
+            Some((primary, _)) => (segments.get_meta_segment().get_primary().get_database(), primary.get_database()),
+            None => return physical,
+        },
+    };
+    if database.eq_ignore_ascii_case(logical_schema.as_str()) {
+        return physical;
+    }
+    for table in stmt_ctx.get_tables() {
+        let mut parts = table.rsplitn(2, '.');
+        let name = logical_table_name(parts.next().unwrap_or(""));
+        let schema = parts.next().map(|schema| schema.trim_matches(|c| c == '`' || c == '"'));
+        if schema.map_or(false, |schema| schema.eq_ignore_ascii_case(logical_schema.as_str())) {
+            let table = unit.get_physical_tables().get(&name.to_lowercase()).cloned().unwrap_or_else(|| name.clone());
+            physical.insert(name.to_lowercase(), format!("{}.{}", database, table));
+        }
+    }
+    physical
+}
+
+/// Single tables join the other tables of the statement on their own segment only: replicated
+/// tables are used from the copy there, and distributed ones must be routed there alone.
+fn colocate_single_tables(tables: &mut [TableRoute]) -> Result<(), ProxyError> {
+    let single = match tables.iter().find(|table| table.kind == TableKind::Single) {
+        Some(single) => single.clone(),
+        None => return Ok(()),
+    };
+    for table in tables.iter_mut() {
+        let colocated = match table.kind {
+            TableKind::Single | TableKind::Distributed => table.groups == single.groups,
+            TableKind::Replicated => match single.groups[0] {
+                SegmentGroup::Data(_) => {
+                    table.groups = single.groups.clone();
+                    true
+                }
+                SegmentGroup::Meta => false,
+            },
+        };
+        if !colocated {
+            return Err(ProxyError::Unsupported(format!("joins of single table {} with {}, which is not on the same segment", single.table, table.table)));
+        }
+    }
+    Ok(())
+}
+
+/// Narrows the replicated tables of a query down to the segments it reads anyway.
+/// Whether the table is the one written by the analysed INSERT, which then has no `VALUES` rows
+/// to route.
+fn is_insert_target(stmt_ctx: &SQLStatementContext, table: &str) -> bool {
+    match stmt_ctx.get_insert_table() {
+        Some(target) => logical_table_name(target).eq_ignore_ascii_case(table),
+        None => false,
+    }
+}
+
+fn read_replicas(route_ctx: &RouteContext, session_groups: &[SegmentGroup], tables: &mut [TableRoute]) {
+    let mut shard_groups: Vec<SegmentGroup> = Vec::new();
+    for table in tables.iter().filter(|table| table.kind == TableKind::Distributed) {
+        for group in &table.groups {
+            if !shard_groups.contains(group) {
+                shard_groups.push(*group);
+            }
+        }
+    }
+    if shard_groups.is_empty() {
+        let data_groups = route_ctx.get_data_groups();
+        let group = session_groups.iter().find(|group| data_groups.contains(group)).or(data_groups.first());
+        shard_groups.extend(group.cloned());
+    }
+    for table in tables.iter_mut().filter(|table| table.kind == TableKind::Replicated) {
+        table.groups = shard_groups.clone();
+    }
+}
+
+/// Relatives joined on their sharding keys have the rows they join on the same segment, which
+/// joins them there; the tables bound together so are routed to the segments all of them may
+/// be on, instead of each to its own.
+fn bind_relatives(route_ctx: &RouteContext, stmt_ctx: &SQLStatementContext, tables: &mut [TableRoute]) {
+    let mut bound = vec![false; tables.len()];
+    for first in 0..tables.len() {
+        if bound[first] || tables[first].kind != TableKind::Distributed {
+            continue;
+        }
+        bound[first] = true;
+        let mut members = vec![first];
+        let mut next = 0;
+        while next < members.len() {
+            let member = members[next];
+            next = next + 1;
+            for other in 0..tables.len() {
+                if !bound[other] && tables[other].kind == TableKind::Distributed
+                    && is_bound(route_ctx.get_dis_rules(), stmt_ctx, tables[member].table.as_str(), tables[other].table.as_str()) {
+                    bound[other] = true;
+                    members.push(other);
+                }
+            }
+        }
+        if members.len() < 2 {
+            continue;
+        }
+        let mut groups = tables[first].groups.clone();
+        for member in &members[1..] {
+            groups.retain(|group| tables[*member].groups.contains(group));
+        }
+        // Contradicting conditions join no rows, any one segment answers that.
+        if groups.is_empty() {
+            groups = route_ctx.get_data_groups().into_iter().take(1).collect();
+        }
+        for member in members {
+            tables[member].groups = groups.clone();
+        }
+    }
+}
+
+/// Whether the relatives are joined on each pair of their sharding keys.
+fn is_bound(dis_rules: &DisRules, stmt_ctx: &SQLStatementContext, table: &str, other: &str) -> bool {
+    let (dis_table, other_table) = match (dis_rules.get_distributed_table(table), dis_rules.get_distributed_table(other)) {
+        (Some(dis_table), Some(other_table)) => (dis_table, other_table),
+        _ => return false,
+    };
+    dis_table.is_relative(other) && !dis_table.get_dis_keys().is_empty()
+        && dis_table.get_dis_keys().iter().zip(other_table.get_dis_keys()).all(|(key, other_key)| {
+            stmt_ctx.get_equalities().iter().any(|equality| equality.binds(table, key, other, other_key))
+        })
+}
+
+/// Data segments which may hold rows of the distributed table the statement touches.
+///
+/// Every WHERE clause of the statement, subqueries included, may be the one scoping the table,
+/// so the segments of all clauses are united; a clause not restricting the table's keys routes
+/// it to every segment.
+fn shard_groups(route_ctx: &RouteContext, table: &str, dis_table: &DisTable, stmt_ctx: &SQLStatementContext, parameters: &[ShardValue]) -> Vec<SegmentGroup> {
+    let data_groups = route_ctx.get_data_groups();
+    let algorithm = match route_ctx.get_algorithm(table) {
+        Some(algorithm) => algorithm,
+        None => return data_groups,
+    };
+    let conditions = stmt_ctx.get_conditions();
+    if conditions.is_empty() || dis_table.get_dis_keys().is_empty() {
+        return data_groups;
+    }
+    let mut segment_ids = BTreeSet::new();
+    for clause in conditions {
+        match clause_segments(algorithm, table, dis_table.get_dis_keys(), clause, parameters) {
+            Some(clause_ids) => segment_ids.extend(clause_ids),
+            None => return data_groups,
+        }
+    }
+    let groups: Vec<SegmentGroup> = data_groups.iter()
+        .filter(|group| match group {
+            SegmentGroup::Data(id) => segment_ids.contains(id),
+            SegmentGroup::Meta => false,
+        })
+        .cloned()
+        .collect();
+    // Contradicting conditions match no row, any one segment answers that.
+    if groups.is_empty() {
+        return data_groups.into_iter().take(1).collect();
+    }
+    groups
+}
+
+/// Segments of the rows one WHERE clause matches, `None` for all of them.
+fn clause_segments(algorithm: &dyn ShardingAlgorithm, table: &str, dis_keys: &[String], clause: &ShardingConditions, parameters: &[ShardValue]) -> Option<BTreeSet<u32>> {
+    let mut key_conditions = Vec::new();
+    for dis_key in dis_keys {
+        key_conditions.push(clause.get_column_conditions(table, dis_key.as_str())?);
+    }
+    let mut segment_ids = BTreeSet::new();
+    for alternative in 0..clause.get_alternatives().len() {
+        let conditions: Vec<&Vec<&ShardingCondition>> = key_conditions.iter().map(|key| &key[alternative]).collect();
+        segment_ids.extend(alternative_segments(algorithm, conditions.as_slice(), parameters)?);
+    }
+    Some(segment_ids)
+}
+
+/// Segments of the rows one alternative matches, given the conditions on each of the keys.
+fn alternative_segments(algorithm: &dyn ShardingAlgorithm, key_conditions: &[&Vec<&ShardingCondition>], parameters: &[ShardValue]) -> Option<BTreeSet<u32>> {
+    if key_conditions.len() == 1 {
+        // Conditions on a single key narrow each other down.
+        let mut segment_ids: Option<BTreeSet<u32>> = None;
+        for condition in key_conditions[0] {
+            let condition_ids = match condition {
+                ShardingCondition::Range(low, high) => {
+                    let low = match low {
+                        Some(bound) => Some((shard_value(bound.get_value(), parameters)?, bound.is_inclusive())),
+                        None => None,
+                    };
+                    let high = match high {
+                        Some(bound) => Some((shard_value(bound.get_value(), parameters)?, bound.is_inclusive())),
+                        None => None,
+                    };
+                    let range_ids = algorithm.shard_range(low.as_ref().map(|(v, i)| (v, *i)), high.as_ref().map(|(v, i)| (v, *i)));
+                    match range_ids {
+                        Some(range_ids) => range_ids.into_iter().collect(),
+                        None => continue,
+                    }
+                }
+                _ => {
+                    let mut point_ids = BTreeSet::new();
+                    for value in point_values(condition, parameters)? {
+                        point_ids.insert(algorithm.shard(&[value])?);
+                    }
+                    point_ids
+                }
+            };
+            segment_ids = Some(match segment_ids {
+                Some(ids) => ids.intersection(&condition_ids).cloned().collect(),
+                None => condition_ids,
+            });
+        }
+        return segment_ids;
+    }
+
+    // Several keys need a point value for each of them.
+    let mut segment_ids = BTreeSet::new();
+    for lookup in point_lookups(key_conditions, parameters)? {
+        segment_ids.insert(algorithm.shard(lookup.as_slice())?);
+    }
+    Some(segment_ids)
+}
+
+/// Values of the keys of every row one alternative matches, `None` unless each key has an `=`
+/// or `IN` condition and they combine into at most `MAX_POINT_LOOKUPS`.
+fn point_lookups(key_conditions: &[&Vec<&ShardingCondition>], parameters: &[ShardValue]) -> Option<Vec<Vec<ShardValue>>> {
+    let mut lookups: Vec<Vec<ShardValue>> = vec![vec![]];
+    for conditions in key_conditions {
+        let values = conditions.iter().filter_map(|condition| point_values(condition, parameters)).next()?;
+        if lookups.len() * values.len() > MAX_POINT_LOOKUPS {
+            return None;
+        }
+        lookups = lookups.into_iter()
+            .flat_map(|lookup| values.iter().map(move |value| {
+                let mut lookup = lookup.clone();
+                lookup.push(value.clone());
+                lookup
+            }))
+            .collect();
+    }
+    Some(lookups)
+}
+
+/// The distributed tables of the statement split into physical tables on the segment group,
+/// with the positions of those which may hold the rows it touches. Tables split alike are
+/// paired position by position, so only relatives joined on their keys are split together.
+fn physical_split<'a>(route_ctx: &'a RouteContext, stmt_ctx: &SQLStatementContext, parameters: &[ShardValue], hints: &RouteHints, tables: &[TableRoute], group: SegmentGroup) -> Result<(Vec<(String, &'a Vec<String>)>, BTreeSet<usize>), ProxyError> {
+    let id = match group {
+        SegmentGroup::Data(id) => id,
+        SegmentGroup::Meta => return Ok((vec![], BTreeSet::new())),
+    };
+    let dis_rules = route_ctx.get_dis_rules();
+    let mut split: Vec<(String, &'a Vec<String>)> = Vec::new();
+    let mut indexes: Option<BTreeSet<usize>> = None;
+    for table in tables.iter().filter(|table| table.kind == TableKind::Distributed && table.groups.contains(&group)) {
+        let dis_table = match dis_rules.get_distributed_table(table.table.as_str()) {
+            Some(dis_table) => dis_table,
+            None => continue,
+        };
+        let physical = match dis_table.get_physical_tables(id) {
+            Some(physical) => physical,
+            None => continue,
+        };
+        if let Some((other, other_physical)) = split.first() {
+            let paired = other_physical.len() == physical.len()
+                && split.iter().any(|(other, _)| is_bound(dis_rules, stmt_ctx, other.as_str(), table.table.as_str()));
+            if !paired {
+                return Err(ProxyError::Unsupported(format!("joins of {} with {}, which are split into physical tables apart", other, table.table)));
+            }
+        }
+        let table_indexes = physical_indexes(route_ctx, table.table.as_str(), stmt_ctx, parameters, hints, id, physical.len())
+            .unwrap_or_else(|| (0..physical.len()).collect());
+        indexes = Some(match indexes {
+            Some(indexes) => indexes.intersection(&table_indexes).cloned().collect(),
+            None => table_indexes,
+        });
+        split.push((table.table.clone(), physical));
+    }
+    let mut indexes = indexes.unwrap_or_default();
+    // Contradicting conditions match no row, any one physical table answers that.
+    if !split.is_empty() && indexes.is_empty() {
+        indexes.insert(0);
+    }
+    Ok((split, indexes))
+}
+
+/// Positions of the distributed table's physical tables on the data segment which may hold the
+/// rows the statement touches, `None` for all of them. A row is in the one at the hash of its
+/// keys, so only point lookups of all keys, or a hinted shard value, narrow them down.
+fn physical_indexes(route_ctx: &RouteContext, table: &str, stmt_ctx: &SQLStatementContext, parameters: &[ShardValue], hints: &RouteHints, id: u32, count: usize) -> Option<BTreeSet<usize>> {
+    let dis_table = route_ctx.get_dis_rules().get_distributed_table(table)?;
+    let algorithm = route_ctx.get_algorithm(table)?;
+    let lookups = match hints.get_shard_value(table) {
+        Some(value) => vec![vec![value.clone()]],
+        None => {
+            let conditions = stmt_ctx.get_conditions();
+            if conditions.is_empty() || dis_table.get_dis_keys().is_empty() {
+                return None;
+            }
+            let mut lookups = Vec::new();
+            for clause in conditions {
+                let mut key_conditions = Vec::new();
+                for dis_key in dis_table.get_dis_keys() {
+                    key_conditions.push(clause.get_column_conditions(table, dis_key.as_str())?);
+                }
+                for alternative in 0..clause.get_alternatives().len() {
+                    let conditions: Vec<&Vec<&ShardingCondition>> = key_conditions.iter().map(|key| &key[alternative]).collect();
+                    lookups.extend(point_lookups(conditions.as_slice(), parameters)?);
+                }
+            }
+            lookups
+        }
+    };
+    Some(lookups.iter()
+        .filter(|lookup| algorithm.shard(lookup.as_slice()) == Some(id))
+        .map(|lookup| hash_key(lookup.as_slice()) as usize % count)
+        .collect())
+}
+
+/// Values of an `=` or `IN` condition.
+fn point_values(condition: &ShardingCondition, parameters: &[ShardValue]) -> Option<Vec<ShardValue>> {
+    match condition {
+        ShardingCondition::Equal(value) => Some(vec![shard_value(value, parameters)?]),
+        ShardingCondition::In(values) => values.iter().map(|value| shard_value(value, parameters)).collect(),
+        ShardingCondition::Range(_, _) => None,
+    }
+}
+
+pub fn shard_value(value: &ConditionValue, parameters: &[ShardValue]) -> Option<ShardValue> {
+    match value {
+        ConditionValue::Literal(literal) => ShardValue::from_literal(literal),
+        ConditionValue::Parameter(index) => parameters.get(*index).cloned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::discovery::{Cluster, SegmentGroup};
+    use crate::error::ProxyError;
+    use crate::handler::parser::sql::analyse::SQLAnalyse;
+    use crate::handler::parser::sql::mysql::{parser, route_hints, RouteHints};
+    use crate::handler::parser::sql::route::{logical_table_name, physical_tables, route, RouteContext, RouteResult, RouteUnit, split_insert, statement_schema, TableKind};
+    use crate::handler::parser::sql::route::value::ShardValue;
+    use crate::handler::parser::sql::SQLStatementContext;
+
+    const CLUSTER: &str = r#"
+name: martlet
+segments:
+  meta_segment:
+    primary: { id: 0, url: "jdbc:mysql://localhost:3306/martlet", username: root, password: root }
+    mirrors: [ ]
+  data_segments:
+    100:
+      primary: { id: 0, url: "jdbc:mysql://localhost:3306/martlet_100", username: root, password: root }
+      mirrors: [ ]
+    200:
+      primary: { id: 0, url: "jdbc:mysql://localhost:3306/martlet_200", username: root, password: root }
+      mirrors: [ ]
+dis_rules:
+  distributed_tables:
+    t_order:
+      dis_keys: [ user_id ]
+      dis_key_types: [ BIGINT ]
+      dis_algorithm: { dis_type: HASH, dis_expression: "" }
+      dis_relatives: [ t_order_item ]
+    t_order_item:
+      dis_keys: [ order_user_id ]
+      dis_key_types: [ INT(11) ]
+      dis_relatives: [ t_order ]
+    t_account:
+      dis_keys: [ user_id ]
+      dis_algorithm: { dis_type: HASH, dis_expression: "" }
+      dis_relatives: [ ]
+      key_update: MOVE
+  replicated_tables: [ t_dept ]
+  single_tables: { t_audit: 200 }
+"#;
+
+    fn route_sql(route_ctx: &RouteContext, sql: &str) -> RouteResult {
+        route_params(route_ctx, sql, vec![])
+    }
+
+    fn route_params(route_ctx: &RouteContext, sql: &str, parameters: Vec<ShardValue>) -> RouteResult {
+        let statement = parser(sql.to_string()).pop().unwrap();
+        let mut stmt_ctx = SQLStatementContext::new(&statement);
+        statement.analyse(&mut stmt_ctx).unwrap();
+        route(route_ctx, &stmt_ctx, sql, parameters.as_slice(), &[], &RouteHints::default()).unwrap()
+    }
+
+    #[test]
+    fn test_route() {
+        let route_ctx = RouteContext::new(Arc::new(Cluster::from_str(CLUSTER)));
+        let data_groups = vec![SegmentGroup::Data(100), SegmentGroup::Data(200)];
+
+        let result = route_sql(&route_ctx, "SELECT * FROM t_order o JOIN t_dept d ON o.dept_id = d.id");
+        assert_eq!(result.get_groups(), data_groups);
+        assert_eq!(result.get_units()[0].get_sql(), "SELECT * FROM t_order o JOIN t_dept d ON o.dept_id = d.id");
+        assert_eq!(result.get_tables()[0].get_kind(), TableKind::Replicated);
+        assert_eq!(result.get_tables()[1].get_kind(), TableKind::Distributed);
+
+        let result = route_sql(&route_ctx, "SELECT * FROM t_dept WHERE id = 1");
+        assert_eq!(result.get_groups(), vec![SegmentGroup::Data(100)]);
+
+        let result = route_sql(&route_ctx, "UPDATE t_dept SET name = 'x' WHERE id = 1");
+        assert_eq!(result.get_groups(), data_groups);
+
+        let result = route_sql(&route_ctx, "INSERT INTO martlet.t_dept (id) VALUES (1)");
+        assert_eq!(result.get_groups(), data_groups);
+
+        let result = route_sql(&route_ctx, "SELECT * FROM t_user");
+        assert_eq!(result.get_groups(), vec![SegmentGroup::Meta]);
+
+        let result = route_sql(&route_ctx, "SET autocommit = 0");
+        assert_eq!(result.get_groups(), vec![SegmentGroup::Meta]);
+
+        assert_eq!(logical_table_name("`martlet`.`t_order`"), "t_order");
+    }
+
+    #[test]
+    fn test_route_hash() {
+        let route_ctx = RouteContext::new(Arc::new(Cluster::from_str(CLUSTER)));
+        let algorithm = route_ctx.get_algorithm("t_order").unwrap();
+        let group_of = |user_id: i64| SegmentGroup::Data(algorithm.shard(&[ShardValue::Int(user_id)]).unwrap());
+
+        let result = route_sql(&route_ctx, "SELECT * FROM t_order WHERE user_id = 42");
+        assert_eq!(result.get_groups(), vec![group_of(42)]);
+
+        let result = route_params(&route_ctx, "SELECT * FROM t_order o WHERE o.user_id = ? AND status = ?",
+                                  vec![ShardValue::Int(42), ShardValue::Int(1)]);
+        assert_eq!(result.get_groups(), vec![group_of(42)]);
+
+        let mut expected = vec![group_of(1), group_of(2), group_of(3), group_of(4)];
+        expected.sort_by_key(|group| match group { SegmentGroup::Data(id) => *id, SegmentGroup::Meta => 0 });
+        expected.dedup();
+        let result = route_sql(&route_ctx, "DELETE FROM t_order WHERE user_id IN (1, 2) OR user_id = 3 OR (user_id = 4 AND status = 0)");
+        assert_eq!(result.get_groups(), expected);
+
+        let result = route_sql(&route_ctx, "SELECT * FROM t_order WHERE user_id = 42 OR status = 0");
+        assert_eq!(result.get_groups().len(), 2);
+        let result = route_sql(&route_ctx, "SELECT * FROM t_order WHERE user_id > 42");
+        assert_eq!(result.get_groups().len(), 2);
+    }
+
+    #[test]
+    fn test_route_relatives() {
+        let route_ctx = RouteContext::new(Arc::new(Cluster::from_str(CLUSTER)));
+        let algorithm = route_ctx.get_algorithm("t_order").unwrap();
+        let group_of = |user_id: i64| SegmentGroup::Data(algorithm.shard(&[ShardValue::Int(user_id)]).unwrap());
+        let relative = route_ctx.get_algorithm("t_order_item").unwrap();
+        assert_eq!(relative.shard(&[ShardValue::Int(42)]), algorithm.shard(&[ShardValue::Int(42)]));
+
+        let result = route_sql(&route_ctx, "SELECT * FROM t_order o JOIN t_order_item i ON o.user_id = i.order_user_id WHERE o.user_id = 42");
+        assert_eq!(result.get_groups(), vec![group_of(42)]);
+        assert!(result.get_tables().iter().all(|table| table.get_groups() == &vec![group_of(42)]));
+
+        let result = route_params(&route_ctx, "SELECT * FROM t_order o, t_order_item i WHERE i.order_user_id = ? AND i.order_user_id = o.user_id",
+                                  vec![ShardValue::Int(42)]);
+        assert_eq!(result.get_groups(), vec![group_of(42)]);
+
+        // Not joined on the sharding key, the rows joined may be on any segment.
+        let result = route_sql(&route_ctx, "SELECT * FROM t_order o JOIN t_order_item i ON o.id = i.order_id WHERE o.user_id = 42");
+        assert_eq!(result.get_groups().len(), 2);
+    }
+
+    #[test]
+    fn test_bad_relatives() {
+        let e = Cluster::try_from_str(CLUSTER.replace("dis_relatives: [ t_order ]", "dis_relatives: [ ]").as_str()).unwrap_err();
+        assert!(e.contains("t_order_item"), "{}", e);
+        let e = Cluster::try_from_str(CLUSTER.replace("INT(11)", "VARCHAR(32)").as_str()).unwrap_err();
+        assert!(e.contains("not compatible"), "{}", e);
+        assert!(Cluster::try_from_str(CLUSTER.replace("dis_relatives: [ t_order_item ]", "dis_relatives: [ ]").as_str()).is_err());
+    }
+
+    #[test]
+    fn test_route_replicated() {
+        let route_ctx = RouteContext::new(Arc::new(Cluster::from_str(CLUSTER)));
+        let algorithm = route_ctx.get_algorithm("t_order").unwrap();
+        let group_of = |user_id: i64| SegmentGroup::Data(algorithm.shard(&[ShardValue::Int(user_id)]).unwrap());
+
+        let result = route_sql(&route_ctx, "SELECT * FROM t_order o JOIN t_dept d ON o.dept_id = d.id WHERE o.user_id = 42");
+        assert_eq!(result.get_groups(), vec![group_of(42)]);
+        assert_eq!(result.get_tables()[0].get_groups(), &vec![group_of(42)]);
+
+        let result = route_sql(&route_ctx, "DELETE FROM t_dept WHERE id = 1");
+        assert_eq!(result.get_groups(), vec![SegmentGroup::Data(100), SegmentGroup::Data(200)]);
+
+        let statement = parser(String::from("SELECT * FROM t_dept")).pop().unwrap();
+        let mut stmt_ctx = SQLStatementContext::new(&statement);
+        statement.analyse(&mut stmt_ctx).unwrap();
+        let session_groups = vec![SegmentGroup::Meta, SegmentGroup::Data(200)];
+        let result = route(&route_ctx, &stmt_ctx, "SELECT * FROM t_dept", &[], session_groups.as_slice(), &RouteHints::default()).unwrap();
+        assert_eq!(result.get_groups(), vec![SegmentGroup::Data(200)]);
+    }
+
+    #[test]
+    fn test_route_pinned_tableless() {
+        let route_ctx = RouteContext::new(Arc::new(Cluster::from_str(CLUSTER)));
+        let insert = route_sql(&route_ctx, "INSERT INTO t_order (user_id, status) VALUES (42, 1)");
+        assert_eq!(insert.get_groups().len(), 1);
+        assert_ne!(insert.get_groups()[0], SegmentGroup::Meta);
+
+        let statement = parser(String::from("SELECT LAST_INSERT_ID()")).pop().unwrap();
+        let mut stmt_ctx = SQLStatementContext::new(&statement);
+        statement.analyse(&mut stmt_ctx).unwrap();
+        let result = route(&route_ctx, &stmt_ctx, "SELECT LAST_INSERT_ID()", &[], insert.get_groups().as_slice(), &RouteHints::default()).unwrap();
+        assert_eq!(result.get_groups(), insert.get_groups());
+
+        let result = route(&route_ctx, &stmt_ctx, "SELECT LAST_INSERT_ID()", &[], &[], &RouteHints::default()).unwrap();
+        assert_eq!(result.get_groups(), vec![SegmentGroup::Meta]);
+    }
+
+    #[test]
+    fn test_physical_tables() {
+        let route_ctx = RouteContext::new(Arc::new(Cluster::from_str(CLUSTER)));
+        let statement = parser(String::from("SELECT * FROM martlet.t_order o JOIN t_dept d ON o.dept_id = d.id")).pop().unwrap();
+        let mut stmt_ctx = SQLStatementContext::new(&statement);
+        statement.analyse(&mut stmt_ctx).unwrap();
+
+        let physical = physical_tables(&route_ctx, &stmt_ctx, &RouteUnit::new(SegmentGroup::Data(200), String::new()));
+        assert_eq!(physical.len(), 1);
+        assert_eq!(physical.get("t_order"), Some(&String::from("martlet_200.t_order")));
+        assert!(physical_tables(&route_ctx, &stmt_ctx, &RouteUnit::new(SegmentGroup::Meta, String::new())).is_empty());
+    }
+
+    #[test]
+    fn test_route_insert() {
+        let route_ctx = RouteContext::new(Arc::new(Cluster::from_str(CLUSTER)));
+        let algorithm = route_ctx.get_algorithm("t_order").unwrap();
+        let group_of = |user_id: i64| SegmentGroup::Data(algorithm.shard(&[ShardValue::Int(user_id)]).unwrap());
+        // Two users on different segments.
+        let other = (2..100).find(|user_id| group_of(*user_id) != group_of(1)).unwrap();
+
+        let sql = format!("INSERT INTO t_order (status, user_id) VALUES (0, 1), (?, ?), (0, {})", other);
+        let result = route_params(&route_ctx, sql.as_str(), vec![ShardValue::Int(1), ShardValue::Int(1)]);
+        assert_eq!(result.get_units().len(), 2);
+        for unit in result.get_units() {
+            let expected: Vec<usize> = if unit.get_group() == group_of(1) { vec![0, 1] } else { vec![2] };
+            assert_eq!(unit.get_rows(), Some(&expected));
+        }
+
+        let result = route_sql(&route_ctx, "INSERT INTO t_order (user_id, status) VALUES (1, 0), (1, 1)");
+        assert_eq!(result.get_groups(), vec![group_of(1)]);
+        assert_eq!(result.get_units()[0].get_rows(), None);
+
+        for sql in &["INSERT INTO t_order (status) VALUES (0)", "INSERT INTO t_order (user_id) VALUES (1 + 1)"] {
+            let statement = parser(sql.to_string()).pop().unwrap();
+            let mut stmt_ctx = SQLStatementContext::new(&statement);
+            statement.analyse(&mut stmt_ctx).unwrap();
+            assert!(route(&route_ctx, &stmt_ctx, sql, &[], &[], &RouteHints::default()).is_err());
+        }
+
+        // Without a column list the position of user_id in the rows is unknown.
+        let sql = "INSERT INTO t_order VALUES (1, 0), (2, 0)";
+        let statement = parser(sql.to_string()).pop().unwrap();
+        let mut stmt_ctx = SQLStatementContext::new(&statement);
+        statement.analyse(&mut stmt_ctx).unwrap();
+        match route(&route_ctx, &stmt_ctx, sql, &[], &[], &RouteHints::default()) {
+            Err(ProxyError::Unsupported(what)) => assert!(what.contains("without a column list")),
+            _ => panic!("`{}` is routed", sql),
+        }
+        let sql = "INSERT /*+ MARTLET_SHARD_VALUE(t_order, 1) */ INTO t_order VALUES (1, 0), (1, 1)";
+        let result = route(&route_ctx, &stmt_ctx, sql, &[], &[], &route_hints(sql)).unwrap();
+        assert_eq!(result.get_groups(), vec![group_of(1)]);
+
+        let statement = parser(String::from("INSERT INTO t_order (user_id) VALUES (1), (2), (3)")).pop().unwrap();
+        assert_eq!(split_insert(&statement, &[0, 2]).to_string(), "INSERT INTO t_order (user_id) VALUES (1), (3)");
+    }
+
+    #[test]
+    fn test_route_hints() {
+        let route_ctx = RouteContext::new(Arc::new(Cluster::from_str(CLUSTER)));
+        let algorithm = route_ctx.get_algorithm("t_order").unwrap();
+        let group_of = |user_id: i64| SegmentGroup::Data(algorithm.shard(&[ShardValue::Int(user_id)]).unwrap());
+        let route_hinted = |sql: &str| {
+            let statement = parser(sql.to_string()).pop().unwrap();
+            let mut stmt_ctx = SQLStatementContext::new(&statement);
+            statement.analyse(&mut stmt_ctx).unwrap();
+            route(&route_ctx, &stmt_ctx, sql, &[], &[], &route_hints(sql))
+        };
+
+        let result = route_hinted("SELECT /*+ MARTLET_SHARD_VALUE(t_order, 42) */ * FROM t_order WHERE status = 0").unwrap();
+        assert_eq!(result.get_groups(), vec![group_of(42)]);
+
+        let result = route_hinted("DELETE /*+ MARTLET_SEGMENT(200) */ FROM t_dept WHERE id = 1").unwrap();
+        assert_eq!(result.get_groups(), vec![SegmentGroup::Data(200)]);
+        let result = route_hinted("INSERT /*+ MARTLET_SEGMENT(100) */ INTO t_order (user_id) VALUES (1), (2)").unwrap();
+        assert_eq!(result.get_groups(), vec![SegmentGroup::Data(100)]);
+        assert_eq!(result.get_units()[0].get_rows(), None);
+
+        assert!(route_hinted("SELECT /*+ MARTLET_SEGMENT(300) */ * FROM t_order").is_err());
+
+        // The rows an INSERT ... SELECT writes are not known to split them.
+        assert!(route_hinted("INSERT INTO t_order (user_id, status) SELECT user_id, 1 FROM t_order WHERE user_id = 42").is_err());
+        assert!(route_hinted("INSERT INTO t_order (user_id, status) SELECT user_id, status FROM t_audit").is_err());
+        let result = route_hinted("INSERT /*+ MARTLET_SHARD_VALUE(t_order, 42) */ INTO t_order (user_id, status) SELECT user_id, 1 FROM t_order WHERE user_id = 42").unwrap();
+        assert_eq!(result.get_groups(), vec![group_of(42)]);
+        let result = route_hinted("INSERT /*+ MARTLET_SEGMENT(200) */ INTO t_order (user_id, status) SELECT user_id, status FROM t_audit").unwrap();
+        assert_eq!(result.get_groups(), vec![SegmentGroup::Data(200)]);
+        assert!(route_hinted("SELECT /*+ MARTLET_SHARD_VALUE(t_order_item, 42) */ * FROM t_order_item").is_ok());
+    }
+
+    #[test]
+    fn test_route_key_update() {
+        let route_ctx = RouteContext::new(Arc::new(Cluster::from_str(CLUSTER)));
+        let algorithm = route_ctx.get_algorithm("t_account").unwrap();
+        let group_of = |user_id: i64| SegmentGroup::Data(algorithm.shard(&[ShardValue::Int(user_id)]).unwrap());
+        let other = (2..100).find(|user_id| group_of(*user_id) != group_of(1)).unwrap();
+
+        let sql = format!("UPDATE t_account SET status = 1, user_id = {} WHERE user_id = 1", other);
+        let result = route_sql(&route_ctx, sql.as_str());
+        assert_eq!(result.get_groups(), vec![group_of(1), group_of(other)]);
+        let key_move = result.get_key_move().unwrap();
+        assert_eq!(key_move.get_values(), &vec![ShardValue::Int(other)]);
+        assert_eq!(key_move.get_target(), group_of(other));
+
+        let result = route_params(&route_ctx, "UPDATE t_account SET user_id = ? WHERE user_id = 1", vec![ShardValue::Int(1)]);
+        assert_eq!(result.get_groups(), vec![group_of(1)]);
+        assert!(route_sql(&route_ctx, "UPDATE t_account SET status = 1 WHERE user_id = 1").get_key_move().is_none());
+
+        for sql in &["UPDATE t_order SET user_id = 2 WHERE user_id = 1", "UPDATE t_account SET user_id = user_id + 1"] {
+            let statement = parser(sql.to_string()).pop().unwrap();
+            let mut stmt_ctx = SQLStatementContext::new(&statement);
+            statement.analyse(&mut stmt_ctx).unwrap();
+            assert!(route(&route_ctx, &stmt_ctx, sql, &[], &[], &RouteHints::default()).is_err());
+        }
+
+        // The rows of the relatives would stay behind on the old segment.
+        let e = Cluster::try_from_str(CLUSTER.replace("dis_relatives: [ t_order_item ]", "dis_relatives: [ t_order_item ]\n      key_update: MOVE").as_str()).unwrap_err();
+        assert!(e.contains("dis_relatives"), "{}", e);
+    }
+
+    #[test]
+    fn test_route_single_tables() {
+        let route_ctx = RouteContext::new(Arc::new(Cluster::from_str(CLUSTER)));
+        let algorithm = route_ctx.get_algorithm("t_order").unwrap();
+        let user_on = |group: SegmentGroup| (1..100).find(|user_id| SegmentGroup::Data(algorithm.shard(&[ShardValue::Int(*user_id)]).unwrap()) == group).unwrap();
+        let route_checked = |sql: &str| {
+            let statement = parser(sql.to_string()).pop().unwrap();
+            let mut stmt_ctx = SQLStatementContext::new(&statement);
+            statement.analyse(&mut stmt_ctx).unwrap();
+            route(&route_ctx, &stmt_ctx, sql, &[], &[], &RouteHints::default())
+        };
+
+        assert_eq!(route_sql(&route_ctx, "SELECT * FROM t_audit").get_groups(), vec![SegmentGroup::Data(200)]);
+        assert_eq!(route_sql(&route_ctx, "SELECT * FROM t_audit a JOIN t_dept d ON a.dept_id = d.id").get_groups(), vec![SegmentGroup::Data(200)]);
+        let sql = format!("SELECT * FROM t_audit a JOIN t_order o ON a.order_id = o.id WHERE o.user_id = {}", user_on(SegmentGroup::Data(200)));
+        assert_eq!(route_sql(&route_ctx, sql.as_str()).get_groups(), vec![SegmentGroup::Data(200)]);
+        let result = route_sql(&route_ctx, "WITH recent AS (SELECT * FROM t_order WHERE status = 0) SELECT * FROM recent");
+        assert_eq!(result.get_groups().len(), 2);
+
+        let sql = format!("SELECT * FROM t_audit a JOIN t_order o ON a.order_id = o.id WHERE o.user_id = {}", user_on(SegmentGroup::Data(100)));
+        assert!(route_checked(sql.as_str()).is_err());
+        assert!(route_checked("SELECT * FROM t_audit a JOIN t_order o ON a.order_id = o.id").is_err());
+        assert!(route_checked("SELECT * FROM t_user u JOIN t_dept d ON u.dept_id = d.id").is_err());
+        assert!(route_checked("SELECT * FROM t_user u JOIN t_audit a ON u.id = a.user_id").is_err());
+    }
+
+    #[test]
+    fn test_route_schemas() {
+        let yaml = format!("{}{}", CLUSTER, r#"
+schemas:
+  archive:
+    data_segments: [ 200 ]
+    database: martlet_archive
+    dis_rules:
+      distributed_tables:
+        t_order:
+          dis_keys: [ order_id ]
+          dis_algorithm: { dis_type: HASH, dis_expression: "" }
+          dis_relatives: [ ]
+      replicated_tables: [ t_dept ]
+"#);
+        let cluster = Arc::new(Cluster::from_str(yaml.as_str()));
+        let schema_of = |sql: &str, database: &str| {
+            let statement = parser(sql.to_string()).pop().unwrap();
+            let mut stmt_ctx = SQLStatementContext::new(&statement);
+            statement.analyse(&mut stmt_ctx).unwrap();
+            statement_schema(&cluster, &stmt_ctx, database)
+        };
+
+        assert_eq!(schema_of("SELECT * FROM archive.t_order", "martlet").unwrap(), Some(String::from("archive")));
+        assert_eq!(schema_of("SELECT * FROM t_order", "ARCHIVE").unwrap(), Some(String::from("archive")));
+        assert_eq!(schema_of("SELECT * FROM t_order", "martlet").unwrap(), None);
+        assert_eq!(schema_of("SELECT * FROM martlet.t_order", "archive").unwrap(), None);
+        assert!(schema_of("SELECT * FROM archive.t_order o JOIN martlet.t_dept d ON o.dept_id = d.id", "martlet").is_err());
+
+        let route_ctx = RouteContext::with_schema(cluster.clone(), Some(String::from("archive")));
+        assert_eq!(route_ctx.get_data_groups(), vec![SegmentGroup::Data(200)]);
+        assert!(route_ctx.get_algorithm("t_order").is_some());
+        let result = route_sql(&route_ctx, "SELECT * FROM archive.t_order WHERE user_id = 1");
+        assert_eq!(result.get_groups(), vec![SegmentGroup::Data(200)]);
+
+        let statement = parser(String::from("SELECT * FROM archive.t_order")).pop().unwrap();
+        let mut stmt_ctx = SQLStatementContext::new(&statement);
+        statement.analyse(&mut stmt_ctx).unwrap();
+        let physical = physical_tables(&route_ctx, &stmt_ctx, &RouteUnit::new(SegmentGroup::Data(200), String::new()));
+        assert_eq!(physical.get("t_order"), Some(&String::from("martlet_archive.t_order")));
+        assert!(physical_tables(&route_ctx, &stmt_ctx, &RouteUnit::new(SegmentGroup::Meta, String::new())).is_empty());
+        let route_ctx = RouteContext::new(cluster);
+        assert_eq!(route_ctx.get_data_groups().len(), 2);
+
+        // Without a database of its own the schema's tables are in a database of its name.
+        let cluster = Arc::new(Cluster::from_str(yaml.replace("    database: martlet_archive\n", "").as_str()));
+        let route_ctx = RouteContext::with_schema(cluster, Some(String::from("archive")));
+        assert!(physical_tables(&route_ctx, &stmt_ctx, &RouteUnit::new(SegmentGroup::Data(200), String::new())).is_empty());
+
+        // Its t_order and t_dept would be the default schema's on segment 200.
+        let e = Cluster::try_from_str(yaml.replace("martlet_archive", "martlet_200").as_str()).unwrap_err();
+        assert!(e.contains("martlet_200.t_"), "{}", e);
+    }
+}
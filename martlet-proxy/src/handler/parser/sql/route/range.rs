@@ -0,0 +1,133 @@
+use std::cmp::Ordering;
+
+use crate::discovery::DisRange;
+use crate::handler::parser::sql::route::ShardingAlgorithm;
+use crate::handler::parser::sql::route::value::ShardValue;
+
+struct Range {
+    from: Option<ShardValue>,
+    to: Option<ShardValue>,
+    segment: u32,
+}
+
+impl Range {
+    fn contains(&self, value: &ShardValue) -> bool {
+        let above_from = match &self.from {
+            Some(from) => from.compare(value).map_or(false, |o| o != Ordering::Greater),
+            None => true,
+        };
+        let below_to = match &self.to {
+            Some(to) => to.compare(value).map_or(false, |o| o == Ordering::Greater),
+            None => true,
+        };
+        above_from && below_to
+    }
+
+    /// Whether the range may hold keys between `low` and `high`, each inclusive or not.
+    /// Boundaries which do not compare with the key keep the range.
+    fn overlaps(&self, low: Option<(&ShardValue, bool)>, high: Option<(&ShardValue, bool)>) -> bool {
+        if let (Some((low, _)), Some(to)) = (low, &self.to) {
+            // Keys of the range are below `to`, the wanted ones are at least `low`.
+            if to.compare(low).map_or(false, |o| o != Ordering::Greater) {
+                return false;
+            }
+        }
+        if let (Some((high, inclusive)), Some(from)) = (high, &self.from) {
+            match from.compare(high) {
+                Some(Ordering::Greater) => return false,
+                Some(Ordering::Equal) if !inclusive => return false,
+                _ => {}
+            }
+        }
+        true
+    }
+}
+
+/// `DisType::RANGE`: a key lives on the segment of the first configured range containing it.
+pub struct RangeAlgorithm {
+    ranges: Vec<Range>,
+}
+
+impl RangeAlgorithm {
+    pub fn new(dis_ranges: &[DisRange]) -> Self {
+        let ranges = dis_ranges.iter()
+            .map(|dis_range| Range {
+                from: dis_range.get_from().as_ref().map(ShardValue::from_dis_value),
+                to: dis_range.get_to().as_ref().map(ShardValue::from_dis_value),
+                segment: dis_range.get_segment(),
+            })
+            .collect();
+        RangeAlgorithm {
+            ranges,
+        }
+    }
+}
+
+impl ShardingAlgorithm for RangeAlgorithm {
+    fn shard(&self, values: &[ShardValue]) -> Option<u32> {
+        let value = values.first()?;
+        self.ranges.iter().find(|range| range.contains(value)).map(|range| range.segment)
+    }
+
+    fn shard_range(&self, low: Option<(&ShardValue, bool)>, high: Option<(&ShardValue, bool)>) -> Option<Vec<u32>> {
+        let mut segments: Vec<u32> = self.ranges.iter()
+            .filter(|range| range.overlaps(low, high))
+            .map(|range| range.segment)
+            .collect();
+        segments.sort();
+        segments.dedup();
+        Some(segments)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::discovery::{DisRange, DisValue};
+    use crate::handler::parser::sql::route::range::RangeAlgorithm;
+    use crate::handler::parser::sql::route::ShardingAlgorithm;
+    use crate::handler::parser::sql::route::value::ShardValue;
+
+    fn algorithm(yaml: &str) -> RangeAlgorithm {
+        let ranges: Vec<DisRange> = serde_yaml::from_str(yaml).unwrap();
+        RangeAlgorithm::new(ranges.as_slice())
+    }
+
+    fn text(s: &str) -> ShardValue {
+        ShardValue::from_dis_value(&DisValue::Text(s.to_string()))
+    }
+
+    #[test]
+    fn test_range_int() {
+        let range = algorithm("[ { to: 1000000, segment: 100 }, { from: 1000000, to: 2000000, segment: 200 }, { from: 2000000, segment: 300 } ]");
+        assert_eq!(range.shard(&[ShardValue::Int(-5)]), Some(100));
+        assert_eq!(range.shard(&[ShardValue::Int(999999)]), Some(100));
+        assert_eq!(range.shard(&[ShardValue::Int(1000000)]), Some(200));
+        assert_eq!(range.shard(&[ShardValue::Int(5000000)]), Some(300));
+
+        let (low, high) = (ShardValue::Int(500), ShardValue::Int(1000000));
+        // BETWEEN 500 AND 1000000
+        assert_eq!(range.shard_range(Some((&low, true)), Some((&high, true))), Some(vec![100, 200]));
+        // >= 500 AND < 1000000
+        assert_eq!(range.shard_range(Some((&low, true)), Some((&high, false))), Some(vec![100]));
+        // >= 1000000
+        assert_eq!(range.shard_range(Some((&high, true)), None), Some(vec![200, 300]));
+        // < 500
+        assert_eq!(range.shard_range(None, Some((&low, false))), Some(vec![100]));
+    }
+
+    #[test]
+    fn test_range_date_and_string() {
+        let range = algorithm("[ { from: '2020-01-01', to: '2021-01-01', segment: 100 }, { from: '2021-01-01', to: '2022-01-01', segment: 200 } ]");
+        assert_eq!(range.shard(&[text("2020-12-31 23:59:59")]), Some(100));
+        assert_eq!(range.shard(&[text("2021-01-01")]), Some(200));
+        assert_eq!(range.shard(&[text("2019-06-01")]), None);
+        let (low, high) = (text("2020-06-01"), text("2021-02-01"));
+        assert_eq!(range.shard_range(Some((&low, true)), Some((&high, true))), Some(vec![100, 200]));
+        assert_eq!(range.shard_range(None, Some((&text("2021-01-01"), false))), Some(vec![100]));
+
+        let range = algorithm("[ { to: m, segment: 100 }, { from: m, segment: 200 } ]");
+        assert_eq!(range.shard(&[text("alice")]), Some(100));
+        assert_eq!(range.shard(&[text("zoe")]), Some(200));
+        assert_eq!(range.shard_range(Some((&text("n"), true)), None), Some(vec![200]));
+    }
+}
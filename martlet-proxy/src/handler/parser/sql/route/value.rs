@@ -0,0 +1,143 @@
+use std::cmp::Ordering;
+
+use sqlparser::ast::Value;
+
+use crate::discovery::DisValue;
+use crate::protocol::mysql::packet::binary::PrepareParamValue;
+
+/// A sharding key value in its canonical form, so a text-protocol literal and the binary-protocol
+/// parameter of the same value route alike:
+///
+/// * integers, and decimals and strings which are integers written canonically (`42`, `42.0`, `'42'`), are `Int`;
+/// * other numbers are `Text` of their shortest decimal form (`1.50` is `'1.5'`);
+/// * dates and times are `Text` as MySQL prints them (`'2021-03-04'`, `'2021-03-04 05:06:07.000008'`, `'-25:00:00'`);
+/// * everything else is `Text` of its bytes.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ShardValue {
+    Null,
+    Int(i64),
+    /// Only for values above `i64::MAX`.
+    UInt(u64),
+    Text(Vec<u8>),
+}
+
+impl ShardValue {
+    pub fn from_literal(value: &Value) -> Option<ShardValue> {
+        match value {
+            Value::Number(number, _) => Some(ShardValue::from_number(number)),
+            Value::SingleQuotedString(s) | Value::DoubleQuotedString(s) | Value::NationalStringLiteral(s) => {
+                Some(ShardValue::from_text(s.as_bytes()))
+            }
+            Value::HexStringLiteral(hex) => {
+                let bytes: Option<Vec<u8>> = (0..hex.len()).step_by(2)
+                    .map(|i| hex.get(i..i + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+                    .collect();
+                bytes.map(ShardValue::Text)
+            }
+            Value::Boolean(b) => Some(ShardValue::Int(*b as i64)),
+            Value::Null => Some(ShardValue::Null),
+            _ => None,
+        }
+    }
+
+    pub fn from_param(value: &PrepareParamValue) -> ShardValue {
+        match value {
+            PrepareParamValue::NULL => ShardValue::Null,
+            PrepareParamValue::Bytes(bytes) => ShardValue::from_text(bytes),
+            PrepareParamValue::Int(i) => ShardValue::Int(*i),
+            PrepareParamValue::UInt(u) => ShardValue::from_uint(*u),
+            PrepareParamValue::Float(f) => ShardValue::from_number(f.to_string().as_str()),
+            PrepareParamValue::Double(d) => ShardValue::from_number(d.to_string().as_str()),
+            PrepareParamValue::Date(year, month, day, hour, minute, second, micro_second) => {
+                let mut date = format!("{:04}-{:02}-{:02}", year, month, day);
+                if *hour != 0 || *minute != 0 || *second != 0 || *micro_second != 0 {
+                    date.push_str(format!(" {:02}:{:02}:{:02}", hour, minute, second).as_str());
+                }
+                if *micro_second != 0 {
+                    date.push_str(format!(".{:06}", micro_second).as_str());
+                }
+                ShardValue::Text(date.into_bytes())
+            }
+            PrepareParamValue::Time(is_negative, days, hours, minutes, seconds, micro_seconds) => {
+                let mut time = format!("{}{:02}:{:02}:{:02}",
+                                       if *is_negative { "-" } else { "" },
+                                       *days * 24 + *hours as u32, minutes, seconds);
+                if *micro_seconds != 0 {
+                    time.push_str(format!(".{:06}", micro_seconds).as_str());
+                }
+                ShardValue::Text(time.into_bytes())
+            }
+        }
+    }
+
+    pub fn from_dis_value(value: &DisValue) -> ShardValue {
+        match value {
+            DisValue::Int(i) => ShardValue::Int(*i),
+            DisValue::Text(s) => ShardValue::from_text(s.as_bytes()),
+        }
+    }
+
+    fn from_uint(u: u64) -> ShardValue {
+        if u <= i64::MAX as u64 {
+            ShardValue::Int(u as i64)
+        } else {
+            ShardValue::UInt(u)
+        }
+    }
+
+    fn from_number(number: &str) -> ShardValue {
+        if let Ok(i) = number.parse::<i64>() {
+            return ShardValue::Int(i);
+        }
+        if let Ok(u) = number.parse::<u64>() {
+            return ShardValue::from_uint(u);
+        }
+        let mut number = number.to_string();
+        if number.contains('.') && !number.contains(|c| c == 'e' || c == 'E') {
+            while number.ends_with('0') {
+                number.pop();
+            }
+            if number.ends_with('.') {
+                number.pop();
+                if let Ok(i) = number.parse::<i64>() {
+                    return ShardValue::Int(i);
+                }
+            }
+        }
+        ShardValue::Text(number.into_bytes())
+    }
+
+    pub fn from_text(bytes: &[u8]) -> ShardValue {
+        if let Ok(text) = std::str::from_utf8(bytes) {
+            if let Ok(i) = text.parse::<i64>() {
+                if i.to_string() == text {
+                    return ShardValue::Int(i);
+                }
+            }
+        }
+        ShardValue::Text(bytes.to_vec())
+    }
+
+    /// Integers compare by value and text by its bytes, which orders canonical dates by time;
+    /// NULL, and integers against text, do not compare.
+    pub fn compare(&self, other: &ShardValue) -> Option<Ordering> {
+        match (self, other) {
+            (ShardValue::Int(a), ShardValue::Int(b)) => Some(a.cmp(b)),
+            (ShardValue::UInt(a), ShardValue::UInt(b)) => Some(a.cmp(b)),
+            (ShardValue::Int(_), ShardValue::UInt(_)) => Some(Ordering::Less),
+            (ShardValue::UInt(_), ShardValue::Int(_)) => Some(Ordering::Greater),
+            (ShardValue::Text(a), ShardValue::Text(b)) => Some(a.cmp(b)),
+            _ => None,
+        }
+    }
+
+    /// Bytes the value hashes as: integers as 8 bytes little endian, text as is, NULL as nothing.
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            ShardValue::Null => vec![],
+            ShardValue::Int(i) => i.to_le_bytes().to_vec(),
+            ShardValue::UInt(u) => u.to_le_bytes().to_vec(),
+            ShardValue::Text(bytes) => bytes.clone(),
+        }
+    }
+}
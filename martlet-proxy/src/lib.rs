@@ -16,6 +16,7 @@ pub mod session;
 pub mod discovery;
 pub mod common;
 pub mod config;
+pub mod error;
 
 #[cfg(test)]
 mod tests {
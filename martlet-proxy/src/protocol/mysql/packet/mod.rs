@@ -677,7 +677,7 @@ impl MySQLErrPacket {
             header: 0xff,
             sql_state_marker: "#".to_string(),
             sequence_id,
-            error_code: 0,
+            error_code,
             sql_state: sql_state,
             error_message: error_message,
         }
@@ -1,6 +1,12 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::sync::atomic::{AtomicU64, Ordering};
 
+use sqlparser::ast::{ObjectType, SetVariableValue, Statement, Value};
+
+use crate::discovery::SegmentGroup;
+use crate::handler::mysql::rdbc::BackendConn;
+use crate::handler::mysql::xa::XaTransaction;
+use crate::handler::parser::sql::called_functions;
 use crate::protocol::mysql::constant::MySQLConnectionPhase;
 use crate::protocol::mysql::packet::generate_random_bytes;
 
@@ -16,7 +22,18 @@ pub struct SessionContext {
     character_set: u8,
     user_name: String,
     auth_response: Vec<u8>,
-    database: String,
+    session_state: SessionState,
+    read_consistency: ReadConsistency,
+    /// GTID set of the session's last write on each segment group, the primaries' sets differ.
+    last_gtids: HashMap<SegmentGroup, String>,
+    in_transaction: bool,
+    temporary_tables: HashSet<String>,
+    user_locks: u32,
+    last_insert_id_pending: bool,
+    pinned_conns: HashMap<SegmentGroup, BackendConn>,
+    last_pinned_group: Option<SegmentGroup>,
+    /// The XA transaction the open client transaction runs as.
+    xa: Option<XaTransaction>,
 }
 
 impl SessionContext {
@@ -36,7 +53,16 @@ impl SessionContext {
             character_set: 0,
             user_name: "".to_string(),
             auth_response: vec![],
-            database: "".to_string(),
+            session_state: SessionState::default(),
+            read_consistency: ReadConsistency::Eventual,
+            last_gtids: HashMap::new(),
+            in_transaction: false,
+            temporary_tables: HashSet::new(),
+            user_locks: 0,
+            last_insert_id_pending: false,
+            pinned_conns: HashMap::new(),
+            last_pinned_group: None,
+            xa: None,
         }
     }
 
@@ -77,11 +103,146 @@ impl SessionContext {
     }
 
     pub fn get_database(&self) -> String {
-        self.database.clone()
+        self.session_state.database.clone()
     }
 
     pub fn set_database(&mut self, database: String) {
-        self.database = database;
+        self.session_state.database = database;
+    }
+
+    pub fn get_session_state(&self) -> &SessionState {
+        &self.session_state
+    }
+
+    pub fn get_session_state_mut(&mut self) -> &mut SessionState {
+        &mut self.session_state
+    }
+
+    pub fn get_read_consistency(&self) -> ReadConsistency {
+        self.read_consistency
+    }
+
+    pub fn set_read_consistency(&mut self, read_consistency: ReadConsistency) {
+        self.read_consistency = read_consistency;
+    }
+
+    /// GTID set covering the session's last committed write on the segment group, if it is tracked.
+    pub fn get_last_gtid(&self, group: SegmentGroup) -> Option<String> {
+        self.last_gtids.get(&group).cloned()
+    }
+
+    pub fn set_last_gtid(&mut self, group: SegmentGroup, last_gtid: String) {
+        self.last_gtids.insert(group, last_gtid);
+    }
+
+    /// Whether a client transaction is open, explicitly or through `autocommit=0`.
+    pub fn is_in_transaction(&self) -> bool {
+        self.in_transaction
+    }
+
+    /// Whether the next statement runs in a transaction: one is open, or `autocommit=0` makes
+    /// the statement start one.
+    pub fn is_transactional(&self) -> bool {
+        self.in_transaction || !self.session_state.autocommit
+    }
+
+    /// Follows the transaction boundaries of a successfully executed statement.
+    pub fn track_transaction(&mut self, statement: &Statement) {
+        self.in_transaction = match statement {
+            Statement::StartTransaction { .. } => true,
+            Statement::Commit { .. } => false,
+            Statement::Rollback { savepoint: None, .. } => false,
+            // Turning autocommit back on commits the open transaction.
+            Statement::SetVariable { variable, .. } if variable.value.eq_ignore_ascii_case("autocommit") => {
+                !self.session_state.autocommit
+            }
+            _ => self.in_transaction || !self.session_state.autocommit,
+        };
+    }
+
+    /// Follows the state of a successfully executed statement which lives on its backend
+    /// connection and cannot move to another one.
+    pub fn track_pins(&mut self, statement: &Statement) {
+        match statement {
+            Statement::CreateTable { temporary: true, name, .. } => {
+                self.temporary_tables.insert(name.to_string().to_lowercase());
+            }
+            Statement::Drop { object_type: ObjectType::Table, names, .. } => {
+                for name in names {
+                    self.temporary_tables.remove(&name.to_string().to_lowercase());
+                }
+            }
+            _ => {}
+        }
+        let functions = called_functions(statement);
+        let calls = |name: &str| functions.iter().filter(|function| *function == name).count() as u32;
+        if calls("RELEASE_ALL_LOCKS") > 0 {
+            self.user_locks = 0;
+        }
+        self.user_locks = self.user_locks + calls("GET_LOCK");
+        self.user_locks = self.user_locks.saturating_sub(calls("RELEASE_LOCK"));
+        // The statement right after an INSERT is the one asking for LAST_INSERT_ID().
+        self.last_insert_id_pending = match statement {
+            Statement::Insert { .. } => true,
+            _ => false,
+        };
+    }
+
+    /// Why the session must keep its backend connections, `None` while they can be multiplexed.
+    pub fn get_pin_reason(&self) -> Option<PinReason> {
+        if self.in_transaction {
+            Some(PinReason::Transaction)
+        } else if !self.temporary_tables.is_empty() {
+            Some(PinReason::TemporaryTable)
+        } else if self.user_locks > 0 {
+            Some(PinReason::UserLock)
+        } else if self.last_insert_id_pending {
+            Some(PinReason::LastInsertId)
+        } else {
+            None
+        }
+    }
+
+    /// Statements dropping what pins the session, run when a pinned connection goes back to the pool.
+    pub fn get_pin_cleanup_sql(&self) -> Vec<String> {
+        let mut sqls: Vec<String> = self.temporary_tables.iter()
+            .map(|table| format!("DROP TEMPORARY TABLE IF EXISTS {}", table))
+            .collect();
+        if self.user_locks > 0 {
+            sqls.push("DO RELEASE_ALL_LOCKS()".to_string());
+        }
+        sqls
+    }
+
+    /// Keeps the connection for the segment group while the session is pinned.
+    pub fn pin_conn(&mut self, conn: BackendConn) {
+        self.last_pinned_group = Some(conn.get_group());
+        self.pinned_conns.insert(conn.get_group(), conn);
+    }
+
+    /// Segment groups the session holds a pinned connection for, the one pinned last first.
+    pub fn get_pinned_groups(&self) -> Vec<SegmentGroup> {
+        let mut groups: Vec<SegmentGroup> = self.pinned_conns.keys().cloned().collect();
+        groups.sort_by_key(|group| Some(*group) != self.last_pinned_group);
+        groups
+    }
+
+    pub fn take_pinned_conn(&mut self, group: SegmentGroup) -> Option<BackendConn> {
+        self.pinned_conns.remove(&group)
+    }
+
+    /// Returns the pinned connections to the pool, rolling back what is still open on them.
+    pub fn release_pinned_conns(&mut self) {
+        self.pinned_conns.clear();
+    }
+
+    /// The XA transaction of the client transaction, begun when it first reaches a segment group.
+    pub fn begin_xa(&mut self) -> &XaTransaction {
+        self.xa.get_or_insert_with(XaTransaction::new)
+    }
+
+    pub fn take_xa(&mut self) -> Option<XaTransaction> {
+        self.xa.take()
     }
 
     pub fn cache_prepare_stmt_ctx(&mut self, sql: String, prepare_stmt_ctx: PrepareStatementContext) {
@@ -109,6 +270,16 @@ impl SessionContext {
         self.prepare_stmt_ctx_map.get_mut(&statement_id).unwrap().set_parameter_types(parameter_types);
     }
 
+    pub fn get_prepare_backend_statement_id(&self, statement_id: u64, statement_key: &str) -> Option<u32> {
+        self.prepare_stmt_ctx_map.get(&statement_id)?.get_backend_statement_id(statement_key)
+    }
+
+    pub fn set_prepare_backend_statement_id(&mut self, statement_id: u64, statement_key: String, backend_statement_id: u32) {
+        if let Some(prepare_stmt_ctx) = self.prepare_stmt_ctx_map.get_mut(&statement_id) {
+            prepare_stmt_ctx.set_backend_statement_id(statement_key, backend_statement_id);
+        }
+    }
+
     pub fn get_prepare_columns_count(&self, statement_id: u64) -> u16 {
         self.prepare_stmt_ctx_map.get(&statement_id).unwrap().get_columns_count()
     }
@@ -134,6 +305,174 @@ impl SessionContext {
     }
 }
 
+/// Session state which ties a client session to its backend connections.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PinReason {
+    Transaction,
+    TemporaryTable,
+    UserLock,
+    LastInsertId,
+}
+
+/// Client session state a backend connection must share before running the client's statements.
+/// `None` means the backend default.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SessionState {
+    database: String,
+    names: Option<String>,
+    sql_mode: Option<String>,
+    time_zone: Option<String>,
+    max_execution_time: Option<u64>,
+    autocommit: bool,
+}
+
+impl Default for SessionState {
+    fn default() -> Self {
+        SessionState {
+            database: "".to_string(),
+            names: None,
+            sql_mode: None,
+            time_zone: None,
+            max_execution_time: None,
+            autocommit: true,
+        }
+    }
+}
+
+impl SessionState {
+    pub fn get_database(&self) -> String {
+        self.database.clone()
+    }
+
+    pub fn set_database(&mut self, database: String) {
+        self.database = database;
+    }
+
+    pub fn get_names(&self) -> Option<String> {
+        self.names.clone()
+    }
+
+    pub fn get_sql_mode(&self) -> Option<String> {
+        self.sql_mode.clone()
+    }
+
+    pub fn get_time_zone(&self) -> Option<String> {
+        self.time_zone.clone()
+    }
+
+    /// Session `max_execution_time` in milliseconds, 0 disables it.
+    pub fn get_max_execution_time(&self) -> Option<u64> {
+        self.max_execution_time
+    }
+
+    pub fn get_autocommit(&self) -> bool {
+        self.autocommit
+    }
+
+    /// Records what a successfully executed statement changed, true if it changed anything.
+    pub fn track(&mut self, statement: &Statement) -> bool {
+        let before = self.clone();
+        match statement {
+            Statement::UseDatabase { variable } => self.database = variable.value.clone(),
+            Statement::SetNames { variable } => self.names = Some(variable.value.clone()),
+            Statement::SetVariable { variable, value, .. } => {
+                let name = variable.value.to_lowercase();
+                let name = name.trim_start_matches("@@").trim_start_matches("session.").trim_start_matches("local.");
+                let value = match value.first() {
+                    Some(SetVariableValue::Ident(ident)) if ident.value.eq_ignore_ascii_case("default") => None,
+                    Some(SetVariableValue::Ident(ident)) => Some(ident.value.clone()),
+                    Some(SetVariableValue::Literal(Value::SingleQuotedString(s))) => Some(s.clone()),
+                    Some(SetVariableValue::Literal(v)) => Some(v.to_string()),
+                    None => None,
+                };
+                match name {
+                    "sql_mode" => self.sql_mode = value,
+                    "time_zone" => self.time_zone = value,
+                    "names" => self.names = value,
+                    "max_execution_time" => self.max_execution_time = value.and_then(|v| v.parse::<u64>().ok()),
+                    "autocommit" => {
+                        self.autocommit = match value {
+                            Some(value) => !(value == "0" || value.eq_ignore_ascii_case("off") || value.eq_ignore_ascii_case("false")),
+                            None => true,
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+        *self != before
+    }
+
+    /// Statements bringing a backend connection in state `applied` to this state.
+    /// An empty database leaves the connection's database untouched.
+    pub fn replay_sql(&self, applied: &SessionState) -> Vec<String> {
+        let mut sqls = Vec::new();
+        if !self.database.is_empty() && self.database != applied.database {
+            sqls.push(format!("USE `{}`", self.database.replace('`', "``")));
+        }
+        if self.names != applied.names {
+            sqls.push(match &self.names {
+                Some(names) => format!("SET NAMES {}", names),
+                None => "SET character_set_client = DEFAULT, character_set_results = DEFAULT, collation_connection = DEFAULT".to_string(),
+            });
+        }
+        if self.sql_mode != applied.sql_mode {
+            sqls.push(format!("SET SESSION sql_mode = {}", quote_or_default(&self.sql_mode)));
+        }
+        if self.time_zone != applied.time_zone {
+            sqls.push(format!("SET SESSION time_zone = {}", quote_or_default(&self.time_zone)));
+        }
+        if self.max_execution_time != applied.max_execution_time {
+            sqls.push(match self.max_execution_time {
+                Some(max_execution_time) => format!("SET SESSION max_execution_time = {}", max_execution_time),
+                None => "SET SESSION max_execution_time = DEFAULT".to_string(),
+            });
+        }
+        if self.autocommit != applied.autocommit {
+            sqls.push(format!("SET autocommit = {}", if self.autocommit { 1 } else { 0 }));
+        }
+        sqls
+    }
+}
+
+/// Functions on MySQL's user locks, which belong to the backend connection calling them.
+const USER_LOCK_FUNCTIONS: [&str; 5] = ["GET_LOCK", "RELEASE_LOCK", "RELEASE_ALL_LOCKS", "IS_USED_LOCK", "IS_FREE_LOCK"];
+
+/// Whether the statement takes, releases or inspects user locks.
+pub fn calls_user_lock_functions(statement: &Statement) -> bool {
+    called_functions(statement).iter().any(|function| USER_LOCK_FUNCTIONS.contains(&function.as_str()))
+}
+
+fn quote_or_default(value: &Option<String>) -> String {
+    match value {
+        Some(value) => format!("'{}'", value.replace('\\', "\\\\").replace('\'', "''")),
+        None => "DEFAULT".to_string(),
+    }
+}
+
+/// How reads see the session's own writes, set with `SET martlet_read_consistency = ...`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReadConsistency {
+    /// Any healthy mirror, the session's writes may not be visible yet.
+    Eventual,
+    /// Wait on the chosen mirror until it executed the session's last GTID.
+    WaitGtid,
+    /// Only mirrors which already executed the session's last GTID, else the primary.
+    PickGtid,
+}
+
+impl ReadConsistency {
+    pub fn value_of(value: &str) -> Option<Self> {
+        match value.to_lowercase().as_str() {
+            "eventual" => Some(ReadConsistency::Eventual),
+            "wait_gtid" => Some(ReadConsistency::WaitGtid),
+            "pick_gtid" => Some(ReadConsistency::PickGtid),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct PrepareStatementContext {
     statement_id: u64,
@@ -141,6 +480,7 @@ pub struct PrepareStatementContext {
     columns_count: u16,
     sql: Vec<u8>,
     parameter_types: Vec<(u8, u8)>,
+    backend_statement_ids: HashMap<String, u32>,
 }
 
 impl PrepareStatementContext {
@@ -154,6 +494,7 @@ impl PrepareStatementContext {
             columns_count,
             sql,
             parameter_types: vec![],
+            backend_statement_ids: HashMap::new(),
         }
     }
 
@@ -180,6 +521,15 @@ impl PrepareStatementContext {
     pub fn set_parameter_types(&mut self, parameter_types: Vec<(u8, u8)>) {
         self.parameter_types = parameter_types;
     }
+
+    /// Backend statement id on the connection with the given statement key, once executed there.
+    pub fn get_backend_statement_id(&self, statement_key: &str) -> Option<u32> {
+        self.backend_statement_ids.get(statement_key).cloned()
+    }
+
+    pub fn set_backend_statement_id(&mut self, statement_key: String, backend_statement_id: u32) {
+        self.backend_statement_ids.insert(statement_key, backend_statement_id);
+    }
 }
 
 lazy_static! {
@@ -271,4 +621,118 @@ mod session_tests {
         println!("{:?}", SessionManager::current());
         assert_eq!(1, 1);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_session_state_replay() {
+        use crate::handler::parser::sql::mysql::parser;
+        use crate::session::mysql::SessionState;
+
+        let mut state = SessionState::default();
+        assert!(state.track(&parser("SET sql_mode = 'STRICT_TRANS_TABLES'".to_string()).pop().unwrap()));
+        assert!(state.track(&parser("SET time_zone = '+08:00'".to_string()).pop().unwrap()));
+        state.set_database("martlet".to_string());
+
+        let applied = SessionState::default();
+        assert_eq!(vec!["USE `martlet`".to_string(),
+                        "SET SESSION sql_mode = 'STRICT_TRANS_TABLES'".to_string(),
+                        "SET SESSION time_zone = '+08:00'".to_string()], state.replay_sql(&applied));
+        assert!(state.replay_sql(&state).is_empty());
+        assert_eq!(vec!["SET SESSION sql_mode = DEFAULT".to_string(),
+                        "SET SESSION time_zone = DEFAULT".to_string()], applied.replay_sql(&state));
+    }
+
+    #[test]
+    fn test_track_transaction() {
+        use crate::handler::parser::sql::mysql::parser;
+        use crate::session::mysql::SessionContext;
+
+        let mut session_ctx = SessionContext::new(1);
+        let run = |sql: &str, session_ctx: &mut SessionContext| {
+            let statement = parser(sql.to_string()).pop().unwrap();
+            session_ctx.get_session_state_mut().track(&statement);
+            session_ctx.track_transaction(&statement);
+            session_ctx.is_in_transaction()
+        };
+        assert!(!run("SELECT 1", &mut session_ctx));
+        assert!(run("START TRANSACTION", &mut session_ctx));
+        assert!(run("UPDATE t_order SET status = 1", &mut session_ctx));
+        assert!(!run("COMMIT", &mut session_ctx));
+        assert!(run("SET autocommit = 0", &mut session_ctx));
+        assert!(!run("ROLLBACK", &mut session_ctx));
+        assert!(run("SELECT 1", &mut session_ctx));
+        assert!(!run("SET autocommit = 1", &mut session_ctx));
+    }
+
+    #[test]
+    fn test_autocommit_off_after_commit() {
+        use crate::handler::parser::sql::mysql::parser;
+        use crate::session::mysql::SessionContext;
+
+        let mut session_ctx = SessionContext::new(1);
+        let run = |sql: &str, session_ctx: &mut SessionContext| {
+            // What the statement is routed by, before it runs.
+            let transactional = session_ctx.is_transactional();
+            let statement = parser(sql.to_string()).pop().unwrap();
+            session_ctx.get_session_state_mut().track(&statement);
+            session_ctx.track_transaction(&statement);
+            transactional
+        };
+        assert!(!run("SELECT 1", &mut session_ctx));
+        run("SET autocommit = 0", &mut session_ctx);
+        assert!(run("UPDATE t_order SET status = 1", &mut session_ctx));
+        assert!(run("COMMIT", &mut session_ctx));
+        assert!(!session_ctx.is_in_transaction());
+        assert!(run("SELECT * FROM t_order", &mut session_ctx));
+        assert!(run("UPDATE t_order SET status = 2", &mut session_ctx));
+        assert!(session_ctx.is_in_transaction());
+        run("SET autocommit = 1", &mut session_ctx);
+        assert!(!session_ctx.is_transactional());
+    }
+
+    #[test]
+    fn test_track_pins() {
+        use crate::handler::parser::sql::mysql::parser;
+        use crate::session::mysql::{PinReason, SessionContext};
+
+        let mut session_ctx = SessionContext::new(1);
+        let run = |sql: &str, session_ctx: &mut SessionContext| {
+            session_ctx.track_pins(&parser(sql.to_string()).pop().unwrap());
+            session_ctx.get_pin_reason()
+        };
+        assert_eq!(None, run("SELECT 1", &mut session_ctx));
+        assert_eq!(Some(PinReason::TemporaryTable), run("CREATE TEMPORARY TABLE tmp_order (id INT)", &mut session_ctx));
+        assert_eq!(None, run("DROP TABLE tmp_order", &mut session_ctx));
+        assert_eq!(Some(PinReason::UserLock), run("SELECT GET_LOCK('job', 10)", &mut session_ctx));
+        assert_eq!(Some(PinReason::UserLock), run("SELECT 'GET_LOCK(' AS note", &mut session_ctx));
+        assert_eq!(None, run("SELECT RELEASE_LOCK('job')", &mut session_ctx));
+        assert_eq!(Some(PinReason::UserLock), run("SELECT get_lock('a', 10), GET_LOCK('b', 10)", &mut session_ctx));
+        assert_eq!(None, run("SELECT RELEASE_ALL_LOCKS()", &mut session_ctx));
+        assert_eq!(Some(PinReason::LastInsertId), run("INSERT INTO t_order (status) VALUES (1)", &mut session_ctx));
+        assert_eq!(None, run("SELECT LAST_INSERT_ID()", &mut session_ctx));
+    }
+
+    #[test]
+    fn test_calls_user_lock_functions() {
+        use crate::handler::parser::sql::mysql::parser;
+        use crate::session::mysql::calls_user_lock_functions;
+
+        let calls = |sql: &str| calls_user_lock_functions(&parser(sql.to_string()).pop().unwrap());
+        assert!(calls("SELECT GET_LOCK('job', 10)"));
+        assert!(calls("SELECT release_lock('job')"));
+        assert!(calls("SELECT IS_USED_LOCK('job')"));
+        assert!(calls("SELECT id FROM t_order WHERE IS_FREE_LOCK(CONCAT('order_', id)) = 1"));
+        assert!(!calls("SELECT 'GET_LOCK(' AS note FROM t_order"));
+        assert!(!calls("SELECT COUNT(*) FROM t_order"));
+    }
+
+    #[test]
+    fn test_last_gtid_per_group() {
+        use crate::discovery::SegmentGroup;
+        use crate::session::mysql::SessionContext;
+
+        let mut session_ctx = SessionContext::new(1);
+        session_ctx.set_last_gtid(SegmentGroup::Data(1), String::from("3e11fa47-71ca-11e1-9e33-c80aa9429562:1-5"));
+        assert_eq!(Some(String::from("3e11fa47-71ca-11e1-9e33-c80aa9429562:1-5")), session_ctx.get_last_gtid(SegmentGroup::Data(1)));
+        assert_eq!(None, session_ctx.get_last_gtid(SegmentGroup::Data(2)));
+    }
+}